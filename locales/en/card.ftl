@@ -0,0 +1,15 @@
+card-debt-none = Nothing left to do
+card-debt-label = Remaining:
+card-debt-count = { $count ->
+    [one] { $count } task
+   *[other] { $count } tasks
+}
+card-project-none = No projects
+card-project-label = Projects:
+card-project-count = { $total ->
+    [one] { $total } project
+   *[other] { $total } projects
+}
+project-episode = Episode { $episode } of { $episodes }
+project-episode-single = Episode { $episode }
+nowplaying-requester = Requested by { $name }
@@ -0,0 +1,9 @@
+card-debt-none = Tidak ada utang
+card-debt-label = Sisa utang:
+card-debt-count = { $count } utang
+card-project-none = Tidak ada garapan
+card-project-label = Proyek:
+card-project-count = { $total } garapan
+project-episode = Episode { $episode } dari { $episodes }
+project-episode-single = Episode { $episode }
+nowplaying-requester = Diminta oleh { $name }
@@ -0,0 +1,9 @@
+card-debt-none = 残りの作業はありません
+card-debt-label = 残りの作業：
+card-debt-count = { $count } 件
+card-project-none = プロジェクトはありません
+card-project-label = プロジェクト：
+card-project-count = { $total } 件
+project-episode = 第{ $episode }話 / 全{ $episodes }話
+project-episode-single = 第{ $episode }話
+nowplaying-requester = { $name } さんのリクエスト
@@ -0,0 +1,324 @@
+/// Analytics events, sent to the backend picked by `ANALYTICS_BACKEND`
+///
+/// Plausible, Umami, a local JSONL file and a no-op sink are supported. Events are sent in
+/// the background so a slow analytics server never delays a response.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use reqwest::header::{self, HeaderMap};
+use serde::Serialize;
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+use tracing::{debug, info, warn};
+
+use crate::{
+    env::get_env, prelude::is_private_ip, upstream::Upstream, AnalyticsEvent, AppState,
+    VisitorMetadata,
+};
+
+#[async_trait]
+pub trait AnalyticsSink: Send + Sync {
+    /// Name of the backend, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Deliver one event with the visitor it came from.
+    async fn send(&self, event: &AnalyticsEvent, metadata: &VisitorMetadata) -> anyhow::Result<()>;
+}
+
+/// Headers identifying the visitor to analytics servers: public IPs and their user agent.
+fn visitor_headers(metadata: &VisitorMetadata) -> HeaderMap {
+    let real_ip: Vec<String> = metadata
+        .ip_address
+        .iter()
+        .filter(|&&ip| !is_private_ip(ip))
+        .map(|ip| ip.to_string())
+        .collect();
+
+    let mut headers = HeaderMap::new();
+    if let Ok(real_ip) = real_ip.join(", ").parse() {
+        headers.insert("X-Forwarded-For", real_ip);
+    }
+    headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+    // Visitors are told apart by their user agent, so forward theirs.
+    if let Ok(user_agent) = metadata.user_agent.parse() {
+        headers.insert(header::USER_AGENT, user_agent);
+    }
+    headers
+}
+
+/// POST a JSON body, failing on anything but a 2xx answer.
+async fn post_json(
+    upstream: &Upstream,
+    url: String,
+    body: String,
+    headers: HeaderMap,
+) -> anyhow::Result<()> {
+    let response = upstream
+        .client()
+        .post(url)
+        .body(body)
+        .headers(headers)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        anyhow::bail!("Rejected with {}", response.status());
+    }
+    Ok(())
+}
+
+pub struct PlausibleSink {
+    upstream: Arc<Upstream>,
+    endpoint: String,
+    domain: String,
+}
+
+#[async_trait]
+impl AnalyticsSink for PlausibleSink {
+    fn name(&self) -> &'static str {
+        "plausible"
+    }
+
+    async fn send(&self, event: &AnalyticsEvent, metadata: &VisitorMetadata) -> anyhow::Result<()> {
+        let body = serde_json::to_string(&AnalyticsEvent {
+            name: event.name.clone(),
+            url: event.url.clone(),
+            props: event.props.clone(),
+            domain: Some(self.domain.clone()),
+        })?;
+        let headers = visitor_headers(metadata);
+        debug!("Sending plausible event: {} // {:?}", body, headers);
+        post_json(
+            &self.upstream,
+            format!("{}/api/event", self.endpoint),
+            body,
+            headers,
+        )
+        .await
+    }
+}
+
+pub struct UmamiSink {
+    upstream: Arc<Upstream>,
+    endpoint: String,
+    website_id: String,
+    /// Host name the events are recorded under, from `SERVER_HOSTNAME`.
+    hostname: Option<String>,
+}
+
+#[async_trait]
+impl AnalyticsSink for UmamiSink {
+    fn name(&self) -> &'static str {
+        "umami"
+    }
+
+    async fn send(&self, event: &AnalyticsEvent, metadata: &VisitorMetadata) -> anyhow::Result<()> {
+        let mut payload = serde_json::json!({
+            "website": self.website_id,
+            "url": event.url,
+            "data": event.props,
+        });
+        if let Some(hostname) = &self.hostname {
+            payload["hostname"] = hostname.clone().into();
+        }
+        // Umami records page views as events without a name.
+        if event.name != "pageview" {
+            payload["name"] = event.name.clone().into();
+        }
+        let body = serde_json::json!({ "type": "event", "payload": payload });
+        let headers = visitor_headers(metadata);
+        debug!("Sending umami event: {} // {:?}", body, headers);
+        post_json(
+            &self.upstream,
+            format!("{}/api/send", self.endpoint),
+            body.to_string(),
+            headers,
+        )
+        .await
+    }
+}
+
+/// One line of the JSONL log. Visitor IPs are left out, only the user agent is kept.
+#[derive(Serialize)]
+struct JsonlRecord<'a> {
+    timestamp: String,
+    name: &'a str,
+    url: &'a str,
+    props: Option<&'a serde_json::Value>,
+    user_agent: &'a str,
+}
+
+/// Appends every event as a JSON line to a local file.
+pub struct JsonlSink {
+    file: Mutex<File>,
+}
+
+impl JsonlSink {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(JsonlSink {
+            file: Mutex::new(File::from_std(file)),
+        })
+    }
+}
+
+#[async_trait]
+impl AnalyticsSink for JsonlSink {
+    fn name(&self) -> &'static str {
+        "jsonl"
+    }
+
+    async fn send(&self, event: &AnalyticsEvent, metadata: &VisitorMetadata) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&JsonlRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            name: &event.name,
+            url: &event.url,
+            props: event.props.as_ref(),
+            user_agent: &metadata.user_agent,
+        })?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Drops every event, used when no backend is configured.
+pub struct NoopSink;
+
+#[async_trait]
+impl AnalyticsSink for NoopSink {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    async fn send(&self, _: &AnalyticsEvent, _: &VisitorMetadata) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps every event in memory so tests can check what handlers report.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySink {
+    pub events: std::sync::Mutex<Vec<(AnalyticsEvent, VisitorMetadata)>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl AnalyticsSink for MemorySink {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn send(&self, event: &AnalyticsEvent, metadata: &VisitorMetadata) -> anyhow::Result<()> {
+        self.events
+            .lock()
+            .unwrap()
+            .push((event.clone(), metadata.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl MemorySink {
+    /// Wait for the last event reported through `state`, then take every recorded event.
+    pub async fn take(&self, state: &AppState) -> Vec<(AnalyticsEvent, VisitorMetadata)> {
+        if let Some(handle) = state.join_handle.lock().await.take() {
+            handle.await.unwrap();
+        }
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+fn non_empty_env(key: &str) -> Option<String> {
+    get_env(key).ok().filter(|value| !value.is_empty())
+}
+
+/// Pick the sink from `ANALYTICS_BACKEND`, see `.env.example`.
+///
+/// Without a backend, Plausible is used when `PLAUSIBLE_ENDPOINT` and `PLAUSIBLE_DOMAIN`
+/// are both set, like before backends could be chosen.
+pub fn from_env(upstream: Arc<Upstream>) -> Arc<dyn AnalyticsSink> {
+    let plausible = || {
+        let endpoint = non_empty_env("PLAUSIBLE_ENDPOINT")?;
+        let domain = non_empty_env("PLAUSIBLE_DOMAIN")?;
+        Some(PlausibleSink {
+            upstream: upstream.clone(),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            domain,
+        })
+    };
+    let backend = non_empty_env("ANALYTICS_BACKEND");
+    let sink: Option<Arc<dyn AnalyticsSink>> = match backend.as_deref() {
+        None => plausible().map(|sink| Arc::new(sink) as _),
+        Some("plausible") => {
+            let sink = plausible();
+            if sink.is_none() {
+                warn!("Plausible needs PLAUSIBLE_ENDPOINT and PLAUSIBLE_DOMAIN");
+            }
+            sink.map(|sink| Arc::new(sink) as _)
+        }
+        Some("umami") => {
+            match (
+                non_empty_env("UMAMI_ENDPOINT"),
+                non_empty_env("UMAMI_WEBSITE_ID"),
+            ) {
+                (Some(endpoint), Some(website_id)) => Some(Arc::new(UmamiSink {
+                    upstream: upstream.clone(),
+                    endpoint: endpoint.trim_end_matches('/').to_string(),
+                    website_id,
+                    hostname: non_empty_env("SERVER_HOSTNAME")
+                        .map(|host| host.split(':').next().unwrap_or_default().to_string()),
+                })),
+                _ => {
+                    warn!("Umami needs UMAMI_ENDPOINT and UMAMI_WEBSITE_ID");
+                    None
+                }
+            }
+        }
+        Some("jsonl") => {
+            let path =
+                non_empty_env("ANALYTICS_JSONL_PATH").unwrap_or("analytics.jsonl".to_string());
+            match JsonlSink::open(&path) {
+                Ok(sink) => Some(Arc::new(sink)),
+                Err(err) => {
+                    warn!("Failed to open analytics log {}: {}", path, err);
+                    None
+                }
+            }
+        }
+        Some("none") => None,
+        Some(other) => {
+            warn!("Unknown analytics backend: `{}`", other);
+            None
+        }
+    };
+    let sink = sink.unwrap_or_else(|| Arc::new(NoopSink));
+    info!("Analytics backend: {}", sink.name());
+    sink
+}
+
+/// Send `event` through the configured sink in the background.
+pub async fn report_event(state: AppState, event: AnalyticsEvent, metadata: VisitorMetadata) {
+    let sink = state.analytics.clone();
+    let analytics_events = state.metrics.analytics_events.clone();
+    let mut lock = state.join_handle.lock().await;
+    *lock = Some(tokio::spawn(async move {
+        debug!("Reporting event: {:?}", event);
+        debug!("Metadata: {:?}", &metadata);
+        let result = match sink.send(&event, &metadata).await {
+            Ok(()) => {
+                debug!("Sent {} event: {:?}", sink.name(), event);
+                "success"
+            }
+            Err(err) => {
+                warn!("Failed to send {} event: {}", sink.name(), err);
+                "failure"
+            }
+        };
+        analytics_events.with_label_values(&[result]).inc();
+    }))
+}
@@ -0,0 +1,354 @@
+/// In-memory, content-addressed cache for rendered images, backed by the disk cache
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::RgbaImage;
+use lru::LruCache;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::task;
+
+use crate::{
+    analytics::report_event,
+    disk_cache::{DiskCache, DiskCacheStats},
+    encoding::{self, Negotiated},
+    palette,
+    prelude::AppError,
+    AnalyticsEvent, AppState, VisitorMetadata,
+};
+
+const DISK_NAMESPACE: &str = "renders";
+
+/// A rendered image with its strong ETag.
+#[derive(Clone)]
+pub struct CachedRender {
+    pub data: Vec<u8>,
+    pub etag: String,
+}
+
+impl CachedRender {
+    pub fn new(data: Vec<u8>) -> Self {
+        let etag = format!("\"{}\"", hash_hex(&[&data]));
+        CachedRender { data, etag }
+    }
+
+    /// Short content hash, used for stable file names.
+    pub fn content_id(&self) -> &str {
+        &self.etag[1..17]
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    /// Lookups answered from memory or disk, like the `cache` analytics property.
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+    pub disk: Option<DiskCacheStats>,
+}
+
+pub struct RenderCache {
+    /// `None` when the cache is disabled.
+    entries: Option<Mutex<LruCache<String, CachedRender>>>,
+    capacity: usize,
+    disk: Option<Arc<DiskCache>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+fn hash_hex(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+impl RenderCache {
+    /// Create a cache holding up to `capacity` renders in memory, `0` disables it.
+    ///
+    /// Renders are also persisted to `disk` when given, surviving restarts.
+    pub fn new(capacity: usize, disk: Option<Arc<DiskCache>>) -> Self {
+        RenderCache {
+            entries: NonZeroUsize::new(capacity).map(|cap| Mutex::new(LruCache::new(cap))),
+            capacity,
+            disk,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Build a cache key from everything that affects the rendered output.
+    ///
+    /// The crate version is always included so a deploy never serves stale renders.
+    pub fn key(parts: &[&str]) -> String {
+        let mut all: Vec<&[u8]> = vec![env!("CARGO_PKG_VERSION").as_bytes()];
+        all.extend(parts.iter().map(|part| part.as_bytes()));
+        hash_hex(&all)
+    }
+
+    /// Look up a render in memory, then on disk.
+    ///
+    /// A render found in either counts as a hit, only missing both is a miss.
+    pub async fn get(&self, key: &str) -> Option<CachedRender> {
+        let found = match self.get_memory(key) {
+            Some(render) => Some(render),
+            None => self.get_disk(key).await,
+        };
+        match found {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        found
+    }
+
+    fn get_memory(&self, key: &str) -> Option<CachedRender> {
+        self.entries
+            .as_ref()
+            .and_then(|entries| entries.lock().unwrap().get(key).cloned())
+    }
+
+    async fn get_disk(&self, key: &str) -> Option<CachedRender> {
+        let data = self.disk.as_ref()?.get(DISK_NAMESPACE, key).await?;
+        let render = CachedRender::new(data);
+        self.insert_memory(key.to_string(), render.clone());
+        Some(render)
+    }
+
+    pub async fn insert(&self, key: String, render: CachedRender) {
+        if let Some(disk) = &self.disk {
+            disk.put(DISK_NAMESPACE, &key, &render.data).await;
+        }
+        self.insert_memory(key, render);
+    }
+
+    fn insert_memory(&self, key: String, render: CachedRender) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().put(key, render);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self
+                .entries
+                .as_ref()
+                .map(|entries| entries.lock().unwrap().len())
+                .unwrap_or_default(),
+            capacity: self.capacity,
+            disk: self.disk.as_ref().map(|disk| disk.stats()),
+        }
+    }
+}
+
+/// Whether the request's `If-None-Match` matches `etag`.
+pub fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// What a card is served as, see [`serve_render`].
+#[derive(Debug, Clone, Copy)]
+pub enum RenderOutput {
+    /// Encoded in the negotiated format, named `<content id>.<name>.<extension>`.
+    Image {
+        negotiated: Negotiated,
+        quality: Option<u8>,
+        name: &'static str,
+    },
+    /// The palette of the card as JSON.
+    Palette,
+}
+
+/// A card served by [`serve_render`].
+pub struct Card {
+    /// Label of the render duration metric, e.g. `large`.
+    pub label: &'static str,
+    /// Route reported to analytics, e.g. `/large`.
+    pub path: &'static str,
+    /// Name used in logs and errors, e.g. `OG Image`.
+    pub description: &'static str,
+    /// Built with [`RenderCache::key`] from everything that affects the output.
+    pub key: String,
+    /// Query string reported to analytics.
+    pub query: String,
+    pub output: RenderOutput,
+    /// Whether the language was negotiated from `Accept-Language`.
+    pub lang_from_header: bool,
+}
+
+/// A card ready to be drawn on a blocking thread.
+pub struct Prepared<D> {
+    /// Draws the card, given the UUID of the request.
+    pub draw: D,
+    /// Whether the result may be cached, `false` when it was drawn with a fallback.
+    pub cacheable: bool,
+}
+
+/// Serve a card from the render cache, or prepare and draw it on a miss.
+///
+/// `prepare` only runs on a miss and does the async work like fetching images. The response
+/// carries an ETag and answers 304 when it matches `If-None-Match`; every request is reported
+/// to analytics, cache hits included.
+pub async fn serve_render<D>(
+    state: AppState,
+    headers: HeaderMap,
+    card: Card,
+    prepare: impl Future<Output = Prepared<D>>,
+) -> Result<Response, AppError>
+where
+    D: FnOnce(&str) -> anyhow::Result<RgbaImage> + Send + 'static,
+{
+    let uuid = uuid::Uuid::new_v4().to_string();
+    let cached = state.render_cache.get(&card.key).await;
+    let cache_status = if cached.is_some() { "hit" } else { "miss" };
+    let render = match cached {
+        Some(render) => Some(render),
+        None => draw_card(&state, &card, &uuid, prepare.await).await,
+    };
+
+    let not_modified = render
+        .as_ref()
+        .is_some_and(|render| is_not_modified(&headers, &render.etag));
+
+    let metadata: VisitorMetadata = headers.into();
+    let event = AnalyticsEvent::default()
+        .with_url(format!("{}?{}", card.path, card.query))
+        .with_props(serde_json::json!({
+            "uuid": uuid,
+            "cache": cache_status,
+        }));
+    report_event(state, event, metadata).await;
+
+    let render =
+        render.ok_or_else(|| AppError::Internal(format!("Error creating {}", card.description)))?;
+
+    let mut resp_headers = HeaderMap::new();
+    match card.output {
+        RenderOutput::Image {
+            negotiated, name, ..
+        } => encoding::insert_image_headers(
+            &mut resp_headers,
+            &negotiated,
+            &format!("{}.{}", render.content_id(), name),
+        )?,
+        RenderOutput::Palette => {
+            resp_headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        }
+    }
+    if card.lang_from_header {
+        resp_headers.append(header::VARY, "Accept-Language".parse().unwrap());
+    }
+    resp_headers.insert(header::ETAG, render.etag.parse().unwrap());
+    // Add cache-control for 10 minutes
+    resp_headers.insert(
+        header::CACHE_CONTROL,
+        "public, max-age=600".parse().unwrap(),
+    );
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, resp_headers).into_response());
+    }
+    Ok((StatusCode::OK, resp_headers, render.data).into_response())
+}
+
+/// Draw and encode a card, caching it unless it was drawn with a fallback.
+async fn draw_card<D>(
+    state: &AppState,
+    card: &Card,
+    uuid: &str,
+    prepared: Prepared<D>,
+) -> Option<CachedRender>
+where
+    D: FnOnce(&str) -> anyhow::Result<RgbaImage> + Send + 'static,
+{
+    let Prepared { draw, cacheable } = prepared;
+    let render_duration = state
+        .metrics
+        .render_duration
+        .with_label_values(&[card.label]);
+    let output = card.output;
+    let uuid = uuid.to_string();
+    let res = task::spawn_blocking(move || {
+        let timer = render_duration.start_timer();
+        let image = draw(&uuid);
+        timer.observe_duration();
+        let image = image?;
+        match output {
+            RenderOutput::Image {
+                negotiated,
+                quality,
+                ..
+            } => encoding::encode_image(&image, negotiated.format, quality),
+            RenderOutput::Palette => Ok(serde_json::to_vec(&palette::extract(&image))?),
+        }
+    })
+    .await;
+
+    match res {
+        Ok(Ok(data)) => {
+            let render = CachedRender::new(data);
+            if cacheable {
+                state
+                    .render_cache
+                    .insert(card.key.clone(), render.clone())
+                    .await;
+            }
+            Some(render)
+        }
+        Ok(Err(err)) => {
+            tracing::error!("Error creating {}: {}", card.description, err);
+            None
+        }
+        Err(err) => {
+            tracing::error!("Error creating {}: {}", card.description, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn disk_hits_count_as_hits() {
+        let dir = std::env::temp_dir().join(format!("render-cache-test-{}", std::process::id()));
+        std::env::set_var("DISK_CACHE_DIR", &dir);
+        let disk = DiskCache::from_env().map(Arc::new);
+        std::env::remove_var("DISK_CACHE_DIR");
+        assert!(disk.is_some());
+
+        let cache = RenderCache::new(4, disk.clone());
+        cache
+            .insert("cached".to_string(), CachedRender::new(vec![1, 2, 3]))
+            .await;
+        // A fresh memory layer only finds the render on disk.
+        let cache = RenderCache::new(4, disk);
+        assert!(cache.get("cached").await.is_some());
+        assert!(cache.get("cached").await.is_some());
+        assert!(cache.get("missing").await.is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+/// Persistent on-disk cache shared by renders and music thumbnails
+///
+/// Every entry is a single file named after its key. The file starts with the creation
+/// time (used for the TTL) and its modification time is bumped on every read, which is
+/// what the LRU eviction sorts on. Writes go to a temporary file first and are renamed
+/// into place, so concurrent writers and readers never see partial entries.
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::env::get_env;
+
+const HEADER_LEN: usize = 8;
+const ENTRY_EXTENSION: &str = "cache";
+/// Prefix of the extension of files being written, see [`DiskCache::put`].
+const TEMP_EXTENSION_PREFIX: &str = "tmp-";
+/// Temporary files older than this are left over from a crashed write.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(10 * 60);
+/// Eviction trims the cache down to this fraction of the limit to avoid thrashing.
+const EVICT_TARGET: f64 = 0.9;
+
+#[derive(Serialize, Debug)]
+pub struct DiskCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size_bytes: u64,
+    pub max_bytes: u64,
+}
+
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    /// Approximate size of the cache, corrected on every eviction pass.
+    size: AtomicU64,
+    evicting: tokio::sync::Mutex<()>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Every cache file below `dir`, with its size and last access time.
+///
+/// Temporary files last written before `stale_before` are removed along the way.
+fn scan_entries(dir: &Path, stale_before: SystemTime) -> Vec<(PathBuf, u64, SystemTime)> {
+    let mut entries = vec![];
+    let namespaces = match std::fs::read_dir(dir) {
+        Ok(namespaces) => namespaces,
+        Err(_) => return entries,
+    };
+    for namespace in namespaces.flatten() {
+        let files = match std::fs::read_dir(namespace.path()) {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+        for file in files.flatten() {
+            let path = file.path();
+            let extension = path.extension().and_then(|ext| ext.to_str());
+            let metadata = match file.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+            match extension {
+                Some(ENTRY_EXTENSION) => entries.push((path, metadata.len(), modified)),
+                Some(ext) if ext.starts_with(TEMP_EXTENSION_PREFIX) && modified < stale_before => {
+                    debug!("Removing stale disk cache file {:?}", path);
+                    let _ = std::fs::remove_file(&path);
+                }
+                _ => {}
+            }
+        }
+    }
+    entries
+}
+
+impl DiskCache {
+    /// Create the cache from `DISK_CACHE_DIR`, `DISK_CACHE_MAX_MB` and `DISK_CACHE_TTL_SECS`.
+    ///
+    /// Returns `None` when `DISK_CACHE_DIR` is unset or cannot be created.
+    pub fn from_env() -> Option<Self> {
+        let dir = get_env("DISK_CACHE_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())?;
+        let max_mb: u64 = get_env("DISK_CACHE_MAX_MB")
+            .ok()
+            .and_then(|mb| mb.parse().ok())
+            .unwrap_or(1024);
+        let ttl_secs: u64 = get_env("DISK_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(7 * 24 * 60 * 60);
+
+        let dir = PathBuf::from(dir);
+        if let Err(err) = std::fs::create_dir_all(&dir) {
+            warn!("Unable to create disk cache at {:?}: {}", dir, err);
+            return None;
+        }
+        // Nothing is being written yet, every temporary file is left over from a crash.
+        let size = scan_entries(&dir, SystemTime::now())
+            .iter()
+            .map(|(_, len, _)| len)
+            .sum();
+        info!(
+            "Disk cache at {:?}: {} MiB used of {} MiB",
+            dir,
+            size / 1024 / 1024,
+            max_mb
+        );
+
+        Some(DiskCache {
+            dir,
+            max_bytes: max_mb * 1024 * 1024,
+            ttl: Duration::from_secs(ttl_secs),
+            size: AtomicU64::new(size),
+            evicting: tokio::sync::Mutex::new(()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    fn entry_path(&self, namespace: &str, key: &str) -> PathBuf {
+        self.dir
+            .join(namespace)
+            .join(format!("{}.{}", key, ENTRY_EXTENSION))
+    }
+
+    /// Read an entry, treating expired or unreadable entries as missing.
+    pub async fn get(&self, namespace: &str, key: &str) -> Option<Vec<u8>> {
+        let path = self.entry_path(namespace, key);
+        let found = match tokio::fs::read(&path).await {
+            Ok(data) if data.len() >= HEADER_LEN => {
+                let mut created = [0u8; HEADER_LEN];
+                created.copy_from_slice(&data[..HEADER_LEN]);
+                let age = now_secs().saturating_sub(u64::from_le_bytes(created));
+                if age > self.ttl.as_secs() {
+                    debug!("Disk cache entry expired: {:?}", path);
+                    if tokio::fs::remove_file(&path).await.is_ok() {
+                        self.shrink(data.len() as u64);
+                    }
+                    None
+                } else {
+                    // Bump the modification time, which acts as the access time for eviction.
+                    let touch_path = path.clone();
+                    let _ = tokio::task::spawn_blocking(move || {
+                        std::fs::File::options()
+                            .append(true)
+                            .open(touch_path)
+                            .and_then(|file| file.set_modified(SystemTime::now()))
+                    })
+                    .await;
+                    Some(data[HEADER_LEN..].to_vec())
+                }
+            }
+            _ => None,
+        };
+
+        match found {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        found
+    }
+
+    fn shrink(&self, len: u64) {
+        let _ = self
+            .size
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |size| {
+                Some(size.saturating_sub(len))
+            });
+    }
+
+    /// Write an entry atomically, evicting old entries when over the size limit.
+    pub async fn put(&self, namespace: &str, key: &str, data: &[u8]) {
+        let path = self.entry_path(namespace, key);
+        let replaced = tokio::fs::metadata(&path)
+            .await
+            .map(|metadata| metadata.len());
+        if let Err(err) = self.write_entry(&path, data).await {
+            warn!("Failed to write disk cache entry {:?}: {}", path, err);
+            return;
+        }
+        if let Ok(len) = replaced {
+            self.shrink(len);
+        }
+
+        let size = self
+            .size
+            .fetch_add((data.len() + HEADER_LEN) as u64, Ordering::Relaxed);
+        if size + (data.len() + HEADER_LEN) as u64 > self.max_bytes {
+            self.evict().await;
+        }
+    }
+
+    async fn write_entry(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut contents = Vec::with_capacity(data.len() + HEADER_LEN);
+        contents.extend_from_slice(&now_secs().to_le_bytes());
+        contents.extend_from_slice(data);
+
+        let temp_path =
+            path.with_extension(format!("{}{}", TEMP_EXTENSION_PREFIX, uuid::Uuid::new_v4()));
+        tokio::fs::write(&temp_path, contents).await?;
+        if let Err(err) = tokio::fs::rename(&temp_path, path).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Remove the least recently used entries until the cache is below the target size.
+    async fn evict(&self) {
+        // Another task is already evicting, no need to do it twice.
+        let _guard = match self.evicting.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return,
+        };
+
+        let dir = self.dir.clone();
+        let target = (self.max_bytes as f64 * EVICT_TARGET) as u64;
+        let res = tokio::task::spawn_blocking(move || {
+            let stale_before = SystemTime::now()
+                .checked_sub(STALE_TEMP_AGE)
+                .unwrap_or(UNIX_EPOCH);
+            let mut entries = scan_entries(&dir, stale_before);
+            let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+            entries.sort_by_key(|(_, _, accessed)| *accessed);
+
+            let mut removed = 0;
+            for (path, len, _) in entries {
+                if size <= target {
+                    break;
+                }
+                if std::fs::remove_file(&path).is_ok() {
+                    size -= len;
+                    removed += 1;
+                }
+            }
+            (size, removed)
+        })
+        .await;
+
+        match res {
+            Ok((size, removed)) => {
+                debug!("Disk cache evicted {} entries, now {} bytes", removed, size);
+                self.size.store(size, Ordering::Relaxed);
+            }
+            Err(err) => warn!("Disk cache eviction failed: {}", err),
+        }
+    }
+
+    pub fn stats(&self) -> DiskCacheStats {
+        DiskCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size_bytes: self.size.load(Ordering::Relaxed),
+            max_bytes: self.max_bytes,
+        }
+    }
+}
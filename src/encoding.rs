@@ -0,0 +1,243 @@
+/// Output format negotiation and encoding for generated images
+use std::io::Cursor;
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, webp::WebPEncoder},
+    ImageFormat, RgbaImage,
+};
+use serde::Deserialize;
+
+use crate::prelude::AppError;
+
+const DEFAULT_JPEG_QUALITY: u8 = 85;
+const DEFAULT_AVIF_QUALITY: u8 = 70;
+/// rav1e speed preset, 10 is the fastest.
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Avif,
+}
+
+/// Query parameters shared by every image endpoint.
+#[derive(Deserialize, Debug, Default)]
+pub struct OutputQuery {
+    pub format: Option<String>,
+    pub quality: Option<u8>,
+}
+
+/// The result of [`negotiate`].
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+    pub format: OutputFormat,
+    /// Whether the `Accept` header was used, the response must then carry `Vary: Accept`.
+    pub used_accept: bool,
+}
+
+impl OutputFormat {
+    /// Order used to break ties between equally weighted `Accept` entries.
+    const PREFERENCE: [OutputFormat; 4] = [
+        OutputFormat::WebP,
+        OutputFormat::Avif,
+        OutputFormat::Jpeg,
+        OutputFormat::Png,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::WebP),
+            "avif" => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        match mime.to_ascii_lowercase().as_str() {
+            "image/png" => Some(OutputFormat::Png),
+            "image/jpeg" | "image/jpg" => Some(OutputFormat::Jpeg),
+            "image/webp" => Some(OutputFormat::WebP),
+            "image/avif" => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+}
+
+/// Pick the output format from the `format` parameter, or the `Accept` header
+/// when the parameter is missing.
+///
+/// Wildcards in `Accept` are ignored so clients that do not care, like most
+/// unfurlers, keep receiving PNG. Returns an error message for unknown formats.
+pub fn negotiate(requested: Option<&str>, headers: &HeaderMap) -> Result<Negotiated, String> {
+    if let Some(requested) = requested {
+        return match OutputFormat::from_name(requested) {
+            Some(format) => Ok(Negotiated {
+                format,
+                used_accept: false,
+            }),
+            None => Err(format!("Unsupported image format: `{}`", requested)),
+        };
+    }
+
+    let mut best: Option<(OutputFormat, f32)> = None;
+    for value in headers.get_all(header::ACCEPT) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for entry in value.split(',') {
+            let mut parts = entry.split(';').map(str::trim);
+            let format = match parts.next().and_then(OutputFormat::from_mime) {
+                Some(format) => format,
+                None => continue,
+            };
+            let weight = parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if weight <= 0.0 {
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some((current, current_weight)) => {
+                    weight > current_weight
+                        || (weight == current_weight && preference(format) < preference(current))
+                }
+            };
+            if better {
+                best = Some((format, weight));
+            }
+        }
+    }
+
+    Ok(Negotiated {
+        format: best.map(|(format, _)| format).unwrap_or(OutputFormat::Png),
+        used_accept: true,
+    })
+}
+
+fn preference(format: OutputFormat) -> usize {
+    OutputFormat::PREFERENCE
+        .iter()
+        .position(|&f| f == format)
+        .unwrap_or(usize::MAX)
+}
+
+/// Encode an intermediate image as PNG, e.g. to hand it to `og_image_writer`.
+pub fn encode_png(image: &RgbaImage) -> anyhow::Result<Vec<u8>> {
+    encode_image(image, OutputFormat::Png, None)
+}
+
+/// Encode an image into the requested format.
+///
+/// `quality` (1-100) applies to the lossy formats, JPEG and AVIF. WebP is always lossless.
+pub fn encode_image(
+    image: &RgbaImage,
+    format: OutputFormat,
+    quality: Option<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+    match format {
+        OutputFormat::Png => image.write_to(&mut buf, ImageFormat::Png)?,
+        OutputFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb = image::DynamicImage::ImageRgba8(image.clone()).into_rgb8();
+            let quality = quality.unwrap_or(DEFAULT_JPEG_QUALITY).clamp(1, 100);
+            rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut buf, quality))?;
+        }
+        OutputFormat::WebP => image.write_with_encoder(WebPEncoder::new_lossless(&mut buf))?,
+        OutputFormat::Avif => {
+            let quality = quality.unwrap_or(DEFAULT_AVIF_QUALITY).clamp(1, 100);
+            image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut buf, AVIF_SPEED, quality,
+            ))?;
+        }
+    }
+    Ok(buf.into_inner())
+}
+
+/// Insert `Content-Type` and `Content-Disposition` (and `Vary` when needed) for an encoded image.
+pub fn insert_image_headers(
+    headers: &mut HeaderMap,
+    negotiated: &Negotiated,
+    file_stem: &str,
+) -> Result<(), AppError> {
+    let format = negotiated.format;
+    let disposition = HeaderValue::from_str(&format!(
+        "inline; filename=\"{}.{}\"",
+        file_stem,
+        format.extension()
+    ))
+    .map_err(|err| AppError::internal(err, "Invalid image file name"))?;
+    headers.insert(header::CONTENT_TYPE, format.mime_type().parse().unwrap());
+    headers.insert(header::CONTENT_DISPOSITION, disposition);
+    if negotiated.used_accept {
+        headers.append(header::VARY, "Accept".parse().unwrap());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn negotiate_prefers_the_format_parameter() {
+        let negotiated = negotiate(Some("JPG"), &accept("image/webp")).unwrap();
+        assert_eq!(negotiated.format, OutputFormat::Jpeg);
+        assert!(!negotiated.used_accept);
+        assert!(negotiate(Some("gif"), &HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn negotiate_uses_accept_weights() {
+        let negotiated = negotiate(None, &accept("image/avif;q=0.9, image/webp;q=0.5")).unwrap();
+        assert_eq!(negotiated.format, OutputFormat::Avif);
+        assert!(negotiated.used_accept);
+
+        // Ties are broken by preference, entries with q=0 are refused.
+        let negotiated = negotiate(None, &accept("image/png, image/webp, image/avif;q=0")).unwrap();
+        assert_eq!(negotiated.format, OutputFormat::WebP);
+    }
+
+    #[test]
+    fn negotiate_defaults_to_png_for_wildcards() {
+        for headers in [
+            HeaderMap::new(),
+            accept("*/*"),
+            accept("image/*, text/html"),
+        ] {
+            assert_eq!(negotiate(None, &headers).unwrap().format, OutputFormat::Png);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+/// Fetching remote images used inside generated cards
+use std::{error::Error, fmt, net::IpAddr};
+
+use image::DynamicImage;
+use reqwest::{dns, header, Url};
+
+use crate::{env::get_env, prelude::is_private_ip, upstream::Upstream};
+
+static USER_AGENT: &str = "naoTimes-OpenGraph/0.1 (+https://naoti.me)";
+/// Largest remote image we are willing to download.
+pub const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+/// Largest width or height of a decoded remote image, guards against decompression bombs.
+const MAX_IMAGE_DIMENSION: u32 = 4096;
+
+/// Hosts remote images may be loaded from.
+pub struct HostAllowlist {
+    /// `None` allows every host.
+    hosts: Option<Vec<String>>,
+}
+
+impl HostAllowlist {
+    /// Read a comma separated host list from `key`, `*` allows every host.
+    pub fn from_env(key: &str, default: &[&str]) -> Self {
+        let hosts: Vec<String> = match get_env(key) {
+            Ok(hosts) if !hosts.trim().is_empty() => hosts
+                .split(',')
+                .map(|host| host.trim().to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+            _ => default.iter().map(|host| host.to_string()).collect(),
+        };
+        if hosts.iter().any(|host| host == "*") {
+            return HostAllowlist { hosts: None };
+        }
+        HostAllowlist { hosts: Some(hosts) }
+    }
+
+    /// Also allow `host`, unless every host is allowed already.
+    pub fn with_host(mut self, host: &str) -> Self {
+        if let Some(hosts) = &mut self.hosts {
+            hosts.push(host.to_ascii_lowercase());
+        }
+        self
+    }
+
+    /// Check a host name, subdomains of an allowed host are allowed too.
+    pub fn allows_host(&self, host: &str) -> bool {
+        let hosts = match &self.hosts {
+            Some(hosts) => hosts,
+            None => return true,
+        };
+        let host = host.to_ascii_lowercase();
+        hosts.iter().any(|allowed| host_matches(&host, allowed))
+    }
+}
+
+/// Whether `host` is `domain` or one of its subdomains.
+pub fn host_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// A URL or resolved address that user supplied URLs may not reach.
+#[derive(Debug)]
+pub struct BlockedUrl(pub String);
+
+impl fmt::Display for BlockedUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for BlockedUrl {}
+
+/// Only allow HTTPS URLs to a host of `hosts` that is not a private IP address.
+pub fn check_url(url: &Url, hosts: &HostAllowlist) -> Result<(), BlockedUrl> {
+    if url.scheme() != "https" {
+        return Err(BlockedUrl(format!(
+            "Unsupported URL scheme: `{}`",
+            url.scheme()
+        )));
+    }
+    let host = url.host_str().unwrap_or_default();
+    if !hosts.allows_host(host) {
+        return Err(BlockedUrl(format!("Host is not allowed: `{}`", host)));
+    }
+    // IP addresses are connected to without going through `PublicResolver`.
+    if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        if is_private_ip(ip) {
+            return Err(BlockedUrl(format!("Address is not allowed: `{}`", ip)));
+        }
+    }
+    Ok(())
+}
+
+/// Find a [`BlockedUrl`] in the sources of a request error.
+pub fn find_blocked<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a BlockedUrl> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(blocked) = err.downcast_ref::<BlockedUrl>() {
+            return Some(blocked);
+        }
+        source = err.source();
+    }
+    None
+}
+
+/// DNS resolver refusing names with a private address, checked right before connecting
+/// so a name can not be pointed somewhere else after [`check_url`].
+pub struct PublicResolver;
+
+impl dns::Resolve for PublicResolver {
+    fn resolve(&self, name: dns::Name) -> dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|addr| is_private_ip(addr.ip())) {
+                let blocked = BlockedUrl(format!(
+                    "`{}` resolves to a private address: `{}`",
+                    host,
+                    addr.ip()
+                ));
+                return Err(Box::new(blocked) as Box<dyn Error + Send + Sync>);
+            }
+            Ok(Box::new(addrs.into_iter()) as dns::Addrs)
+        })
+    }
+}
+
+/// Download and decode a remote image, rejecting non-HTTP URLs and oversized bodies.
+pub async fn fetch_image(upstream: &Upstream, url: &str) -> anyhow::Result<DynamicImage> {
+    fetch_image_limited(upstream, url, MAX_IMAGE_BYTES).await
+}
+
+/// Like [`fetch_image`] but with a custom limit on the downloaded size.
+pub async fn fetch_image_limited(
+    upstream: &Upstream,
+    url: &str,
+    max_bytes: usize,
+) -> anyhow::Result<DynamicImage> {
+    let parsed = Url::parse(url)?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        anyhow::bail!("Unsupported URL scheme: `{}`", parsed.scheme());
+    }
+    upstream.check(&parsed)?;
+
+    let mut headers = header::HeaderMap::new();
+    headers.insert(
+        header::USER_AGENT,
+        header::HeaderValue::from_static(USER_AGENT),
+    );
+    let response = upstream
+        .get_with_headers(parsed, headers)
+        .await?
+        .error_for_status()?;
+    let data = upstream.bytes_limited(response, max_bytes).await?;
+
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    let mut reader = image::ImageReader::new(std::io::Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits);
+    Ok(reader.decode()?)
+}
@@ -0,0 +1,217 @@
+/// Localization of card strings using Fluent message catalogs from `locales/`
+use std::collections::HashMap;
+
+use axum::http::{header, HeaderMap};
+use fluent_bundle::{concurrent::FluentBundle, FluentArgs, FluentResource, FluentValue};
+use intl_memoizer::concurrent::IntlLangMemoizer;
+use tracing::warn;
+use unic_langid::LanguageIdentifier;
+
+pub const DEFAULT_LANG: &str = "id";
+
+struct LocaleSource {
+    tag: &'static str,
+    catalog: &'static str,
+    /// Thousands separator used when formatting numbers.
+    group_separator: char,
+    /// Whether the text needs a font with CJK glyphs.
+    cjk: bool,
+}
+
+static LOCALES: &[LocaleSource] = &[
+    LocaleSource {
+        tag: "id",
+        catalog: include_str!("../locales/id/card.ftl"),
+        group_separator: '.',
+        cjk: false,
+    },
+    LocaleSource {
+        tag: "en",
+        catalog: include_str!("../locales/en/card.ftl"),
+        group_separator: ',',
+        cjk: false,
+    },
+    LocaleSource {
+        tag: "ja",
+        catalog: include_str!("../locales/ja/card.ftl"),
+        group_separator: ',',
+        cjk: true,
+    },
+];
+
+/// A supported language, obtained from [`Catalog::negotiate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lang {
+    pub tag: &'static str,
+    pub cjk: bool,
+}
+
+/// The result of [`Catalog::negotiate`].
+#[derive(Debug, Clone, Copy)]
+pub struct NegotiatedLang {
+    pub lang: Lang,
+    /// Whether `Accept-Language` was used, the response must then carry `Vary: Accept-Language`.
+    pub used_header: bool,
+}
+
+pub struct Catalog {
+    bundles: HashMap<&'static str, FluentBundle<FluentResource>>,
+}
+
+/// A [`Catalog`] bound to a single language.
+pub struct Localizer<'a> {
+    catalog: &'a Catalog,
+    pub lang: Lang,
+}
+
+impl Localizer<'_> {
+    pub fn message(&self, id: &str, args: Option<&FluentArgs>) -> String {
+        self.catalog.message(self.lang, id, args)
+    }
+}
+
+/// Format integers with the thousands separator `SEP`, leaving other numbers to Fluent.
+fn format_grouped<const SEP: char>(value: &FluentValue, _: &IntlLangMemoizer) -> Option<String> {
+    let number = match value {
+        FluentValue::Number(number) => number,
+        _ => return None,
+    };
+    if number.value.fract() != 0.0 || number.value.abs() < 1000.0 {
+        return None;
+    }
+
+    let digits = format!("{}", number.value.abs() as u64);
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(SEP);
+        }
+        grouped.push(digit);
+    }
+    if number.value < 0.0 {
+        grouped.insert(0, '-');
+    }
+    Some(grouped)
+}
+
+impl Catalog {
+    /// Build the catalog from the embedded `locales/` files.
+    pub fn load() -> Self {
+        let mut bundles = HashMap::new();
+        for source in LOCALES {
+            let langid: LanguageIdentifier = source.tag.parse().expect("valid locale tag");
+            let resource = match FluentResource::try_new(source.catalog.to_string()) {
+                Ok(resource) => resource,
+                Err((resource, errors)) => {
+                    warn!("Errors in `{}` catalog: {:?}", source.tag, errors);
+                    resource
+                }
+            };
+
+            let mut bundle = FluentBundle::new_concurrent(vec![langid]);
+            // Unicode isolation marks render as tofu with our fonts.
+            bundle.set_use_isolating(false);
+            bundle.set_formatter(Some(match source.group_separator {
+                '.' => format_grouped::<'.'>,
+                ' ' => format_grouped::<' '>,
+                _ => format_grouped::<','>,
+            }));
+            if let Err(errors) = bundle.add_resource(resource) {
+                warn!("Errors in `{}` catalog: {:?}", source.tag, errors);
+            }
+            bundles.insert(source.tag, bundle);
+        }
+        Catalog { bundles }
+    }
+
+    /// Find a supported language for a tag like `en`, `en-US` or `ja_JP`.
+    pub fn find(&self, tag: &str) -> Option<Lang> {
+        let langid: LanguageIdentifier = tag.replace('_', "-").parse().ok()?;
+        let language = langid.language.as_str();
+        LOCALES
+            .iter()
+            .find(|source| source.tag == language)
+            .map(|source| Lang {
+                tag: source.tag,
+                cjk: source.cjk,
+            })
+    }
+
+    /// Pick the language from the `lang` parameter, or `Accept-Language` when missing.
+    ///
+    /// Returns an error message when `lang` is given but not supported.
+    pub fn negotiate(
+        &self,
+        requested: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Result<NegotiatedLang, String> {
+        if let Some(requested) = requested {
+            return match self.find(requested) {
+                Some(lang) => Ok(NegotiatedLang {
+                    lang,
+                    used_header: false,
+                }),
+                None => Err(format!("Unsupported language: `{}`", requested)),
+            };
+        }
+
+        let mut best: Option<(Lang, f32)> = None;
+        for value in headers.get_all(header::ACCEPT_LANGUAGE) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            for entry in value.split(',') {
+                let mut parts = entry.split(';').map(str::trim);
+                let lang = match parts.next().and_then(|tag| self.find(tag)) {
+                    Some(lang) => lang,
+                    None => continue,
+                };
+                let weight = parts
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                // Earlier entries win ties, like browsers expect.
+                if weight > 0.0 && best.map_or(true, |(_, current)| weight > current) {
+                    best = Some((lang, weight));
+                }
+            }
+        }
+
+        Ok(NegotiatedLang {
+            lang: best
+                .map(|(lang, _)| lang)
+                .unwrap_or_else(|| self.find(DEFAULT_LANG).expect("default language exists")),
+            used_header: true,
+        })
+    }
+
+    pub fn localizer(&self, lang: Lang) -> Localizer<'_> {
+        Localizer {
+            catalog: self,
+            lang,
+        }
+    }
+
+    /// Format a message, falling back to the default language and then the message id.
+    pub fn message(&self, lang: Lang, id: &str, args: Option<&FluentArgs>) -> String {
+        for tag in [lang.tag, DEFAULT_LANG] {
+            let bundle = match self.bundles.get(tag) {
+                Some(bundle) => bundle,
+                None => continue,
+            };
+            let pattern = match bundle.get_message(id).and_then(|message| message.value()) {
+                Some(pattern) => pattern,
+                None => continue,
+            };
+            let mut errors = vec![];
+            let text = bundle.format_pattern(pattern, args, &mut errors);
+            if !errors.is_empty() {
+                warn!("Errors formatting `{}` in `{}`: {:?}", id, tag, errors);
+            }
+            return text.into_owned();
+        }
+        warn!("Missing message `{}`", id);
+        id.to_string()
+    }
+}
@@ -0,0 +1,277 @@
+/// Shared image manipulation helpers
+use image::{imageops::FilterType, DynamicImage, RgbaImage};
+
+/// Scale and center-crop an image so it covers the whole `width`x`height` canvas.
+pub fn cover_fit(image: &DynamicImage, width: u32, height: u32) -> RgbaImage {
+    if image.width() == width && image.height() == height {
+        return image.to_rgba8();
+    }
+    image
+        .resize_to_fill(width, height, FilterType::Lanczos3)
+        .into_rgba8()
+}
+
+/// Create a soft, darkened backdrop from an image, covering `width`x`height`.
+///
+/// The blur runs on a downscaled copy, which is much cheaper than blurring at full size.
+pub fn blurred_backdrop(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    brightness: f32,
+) -> RgbaImage {
+    const DOWNSCALE: u32 = 8;
+    let small = image.resize_to_fill(
+        (width / DOWNSCALE).max(1),
+        (height / DOWNSCALE).max(1),
+        FilterType::Triangle,
+    );
+    let mut backdrop = image::imageops::resize(
+        &image::imageops::blur(&small, 4.),
+        width,
+        height,
+        FilterType::Triangle,
+    );
+    for pixel in backdrop.pixels_mut() {
+        for channel in pixel.0.iter_mut().take(3) {
+            *channel = (*channel as f32 * brightness).round().clamp(0., 255.) as u8;
+        }
+        pixel.0[3] = 255;
+    }
+    backdrop
+}
+
+/// Cover-fit an image into a `diameter` sized circle with anti-aliased edges.
+///
+/// When `ring` is given, a border of that color and width is drawn along the edge.
+pub fn circle_crop(image: &DynamicImage, diameter: u32, ring: Option<([u8; 4], f32)>) -> RgbaImage {
+    let mut circle = cover_fit(image, diameter, diameter);
+    let radius = diameter as f32 / 2.;
+    for (x, y, pixel) in circle.enumerate_pixels_mut() {
+        let dx = x as f32 + 0.5 - radius;
+        let dy = y as f32 + 0.5 - radius;
+        let distance = (dx * dx + dy * dy).sqrt();
+
+        if let Some((color, width)) = ring {
+            // How much of this pixel lies inside the ring, blended over the image.
+            let coverage = (distance - (radius - width) + 0.5).clamp(0., 1.);
+            for (channel, ring) in pixel.0.iter_mut().zip(color) {
+                let base = *channel as f32;
+                *channel = (base + (ring as f32 - base) * coverage).round() as u8;
+            }
+        }
+
+        let coverage = (radius - distance + 0.5).clamp(0., 1.);
+        pixel.0[3] = (pixel.0[3] as f32 * coverage).round() as u8;
+    }
+    circle
+}
+
+fn blend(pixel: &mut image::Rgba<u8>, color: [u8; 4], coverage: f32) {
+    let alpha = color[3] as f32 / 255. * coverage;
+    for (channel, value) in pixel.0.iter_mut().zip(color).take(3) {
+        let base = *channel as f32;
+        *channel = (base + (value as f32 - base) * alpha).round() as u8;
+    }
+    let base = pixel.0[3] as f32;
+    pixel.0[3] = (base + (255. - base) * alpha).round() as u8;
+}
+
+/// Blend `color` over the whole image, `amount` from 0 (untouched) to 1 (solid color).
+pub fn tint(image: &mut RgbaImage, color: [u8; 3], amount: f32) {
+    let [r, g, b] = color;
+    for pixel in image.pixels_mut() {
+        blend(pixel, [r, g, b, 255], amount);
+    }
+}
+
+/// Coverage of a pixel by a horizontal capsule starting at `x`, `length` long and `height` tall.
+fn capsule_coverage(px: f32, py: f32, x: f32, y: f32, length: f32, height: f32) -> f32 {
+    let radius = height / 2.;
+    let center_y = y + radius;
+    let nearest_x = px.clamp(x + radius, (x + length - radius).max(x + radius));
+    let distance = ((px - nearest_x).powi(2) + (py - center_y).powi(2)).sqrt();
+    (radius - distance + 0.5).clamp(0., 1.)
+}
+
+/// Draw a rounded progress bar onto `image`, filled up to `fraction` of its width.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_progress_bar(
+    image: &mut RgbaImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    fraction: f32,
+    fill: [u8; 4],
+    track: [u8; 4],
+) {
+    let (x, y, width, height) = (x as f32, y as f32, width as f32, height as f32);
+    // Keep any progress visible as at least a full circle.
+    let filled = match fraction.clamp(0., 1.) {
+        fraction if fraction > 0. => (width * fraction).max(height),
+        _ => 0.,
+    };
+    let x_end = ((x + width).ceil() as u32).min(image.width());
+    let y_end = ((y + height).ceil() as u32).min(image.height());
+    for py in (y as u32)..y_end {
+        for px in (x as u32)..x_end {
+            let (cx, cy) = (px as f32 + 0.5, py as f32 + 0.5);
+            let pixel = image.get_pixel_mut(px, py);
+            blend(pixel, track, capsule_coverage(cx, cy, x, y, width, height));
+            if filled > 0. {
+                blend(pixel, fill, capsule_coverage(cx, cy, x, y, filled, height));
+            }
+        }
+    }
+}
+
+/// Whether a line of pixels is part of a black letterbox or pillarbox bar.
+fn is_dark_line<'a>(pixels: impl Iterator<Item = &'a image::Rgba<u8>>) -> bool {
+    const DARK_LUMA: u32 = 32;
+    let (mut total, mut bright) = (0u32, 0u32);
+    for pixel in pixels {
+        let [r, g, b, _] = pixel.0;
+        let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+        total += 1;
+        if luma > DARK_LUMA {
+            bright += 1;
+        }
+    }
+    // Allow a few bright pixels from compression noise.
+    bright * 50 <= total
+}
+
+/// Find the area of an image inside black letterbox and pillarbox bars, as `(x, y, width, height)`.
+pub fn content_bounds(image: &RgbaImage) -> (u32, u32, u32, u32) {
+    let (width, height) = image.dimensions();
+    let row_dark = |y: u32| is_dark_line((0..width).map(|x| image.get_pixel(x, y)));
+    let top = (0..height).find(|&y| !row_dark(y));
+    let top = match top {
+        Some(top) => top,
+        // Completely dark, keep everything.
+        None => return (0, 0, width, height),
+    };
+    let bottom = (top..height).rev().find(|&y| !row_dark(y)).unwrap_or(top) + 1;
+
+    let column_dark = |x: u32| is_dark_line((top..bottom).map(|y| image.get_pixel(x, y)));
+    let left = (0..width).find(|&x| !column_dark(x)).unwrap_or(0);
+    let right = (left..width)
+        .rev()
+        .find(|&x| !column_dark(x))
+        .unwrap_or(left)
+        + 1;
+    (left, top, right - left, bottom - top)
+}
+
+/// Crop the black bars around the content of an image.
+pub fn trim_bars(image: &DynamicImage) -> DynamicImage {
+    let rgba = image.to_rgba8();
+    let (x, y, width, height) = content_bounds(&rgba);
+    if (width, height) == rgba.dimensions() {
+        return image.clone();
+    }
+    DynamicImage::ImageRgba8(image::imageops::crop_imm(&rgba, x, y, width, height).to_image())
+}
+
+/// How an image is fitted into the requested size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Crop to a centered square.
+    Cover,
+    /// Scale the whole image until its longest side matches.
+    Contain,
+    /// Place the whole image in a square over a blurred copy of itself.
+    BlurPad,
+}
+
+impl Fit {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "cover" => Some(Fit::Cover),
+            "contain" => Some(Fit::Contain),
+            "blur-pad" => Some(Fit::BlurPad),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fit::Cover => "cover",
+            Fit::Contain => "contain",
+            Fit::BlurPad => "blur-pad",
+        }
+    }
+}
+
+/// Fit an image into a `size` pixels box, keeping its own size when `size` is `None`.
+pub fn fit_image(image: &DynamicImage, size: Option<u32>, fit: Fit) -> RgbaImage {
+    let (width, height) = (image.width(), image.height());
+    match fit {
+        Fit::Cover => {
+            let side = size.unwrap_or(width.min(height));
+            cover_fit(image, side, side)
+        }
+        Fit::Contain => match size {
+            Some(size) => image.resize(size, size, FilterType::Lanczos3).into_rgba8(),
+            None => image.to_rgba8(),
+        },
+        Fit::BlurPad => {
+            let side = size.unwrap_or(width.max(height));
+            let mut canvas = blurred_backdrop(image, side, side, 0.6);
+            let front = image.resize(side, side, FilterType::Lanczos3).into_rgba8();
+            let x = (side - front.width()) / 2;
+            let y = (side - front.height()) / 2;
+            image::imageops::overlay(&mut canvas, &front, x as i64, y as i64);
+            canvas
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    /// A black `width` x `height` image with a gray rectangle at `(x, y, w, h)`.
+    fn letterboxed(width: u32, height: u32, content: (u32, u32, u32, u32)) -> RgbaImage {
+        let (x, y, w, h) = content;
+        RgbaImage::from_fn(width, height, |px, py| {
+            if (x..x + w).contains(&px) && (y..y + h).contains(&py) {
+                Rgba([180, 120, 60, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn content_bounds_finds_letterbox_and_pillarbox_bars() {
+        assert_eq!(
+            content_bounds(&letterboxed(48, 36, (0, 5, 48, 26))),
+            (0, 5, 48, 26)
+        );
+        assert_eq!(
+            content_bounds(&letterboxed(48, 36, (6, 0, 36, 36))),
+            (6, 0, 36, 36)
+        );
+        assert_eq!(
+            content_bounds(&letterboxed(48, 36, (6, 5, 36, 26))),
+            (6, 5, 36, 26)
+        );
+    }
+
+    #[test]
+    fn content_bounds_keeps_whole_images() {
+        assert_eq!(
+            content_bounds(&letterboxed(48, 36, (0, 0, 48, 36))),
+            (0, 0, 48, 36)
+        );
+        // Completely dark images are kept as they are.
+        assert_eq!(
+            content_bounds(&letterboxed(48, 36, (0, 0, 0, 0))),
+            (0, 0, 48, 36)
+        );
+    }
+}
@@ -1,46 +1,81 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use crate::env::get_env;
 use axum::{
+    extract::State,
     http::StatusCode,
+    middleware,
     response::{Html, IntoResponse},
     routing::get,
-    Router,
+    Json, Router,
 };
-use prelude::is_private_ip;
-use reqwest::header::HeaderMap;
 use tokio::{net::TcpListener, sync::Mutex, task::JoinHandle};
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::TraceLayer,
 };
-use tracing::debug;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod analytics;
+mod cache;
+mod disk_cache;
+mod encoding;
 mod env;
+mod fetch;
+mod i18n;
+mod imaging;
+mod metrics;
+mod palette;
 mod prelude;
+mod rate_limit;
 mod routes;
+mod signing;
+mod theme;
+mod upstream;
 
 #[derive(Clone)]
 pub struct AppState {
     join_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Secret used to verify signed `/large` URLs, disabled when unset.
+    signing_secret: Option<Arc<str>>,
+    themes: Arc<theme::ThemeRegistry>,
+    i18n: Arc<i18n::Catalog>,
+    render_cache: Arc<cache::RenderCache>,
+    /// Shared persistent cache, disabled when `DISK_CACHE_DIR` is unset.
+    disk_cache: Option<Arc<disk_cache::DiskCache>>,
+    /// Client for `/large` icons, limited to `ICON_ALLOWED_HOSTS`.
+    icon_upstream: Arc<upstream::Upstream>,
+    /// Pooled client for providers, icons and analytics.
+    upstream: Arc<upstream::Upstream>,
+    /// Client for `/project` covers, limited to `COVER_ALLOWED_HOSTS`.
+    cover_upstream: Arc<upstream::Upstream>,
+    /// Client for user supplied Bandcamp URLs, limited to `BANDCAMP_ALLOWED_HOSTS`.
+    bandcamp_upstream: Arc<upstream::Upstream>,
+    /// Client for images found on Bandcamp pages, which may also come from their CDN.
+    bandcamp_image_upstream: Arc<upstream::Upstream>,
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+    metrics: Arc<metrics::Metrics>,
+    analytics: Arc<dyn analytics::AnalyticsSink>,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct PlausibleEvent {
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AnalyticsEvent {
     name: String,
     url: String,
     props: Option<serde_json::Value>,
     domain: Option<String>,
 }
 
-#[derive(Debug)]
-pub struct PlausibleMetadata {
+#[derive(Debug, Clone)]
+pub struct VisitorMetadata {
     pub user_agent: String,
     pub ip_address: Vec<IpAddr>,
 }
 
-impl PlausibleEvent {
+impl AnalyticsEvent {
     pub fn with_name(mut self, name: &str) -> Self {
         self.name = name.to_string();
         self
@@ -57,7 +92,7 @@ impl PlausibleEvent {
     }
 }
 
-impl Default for PlausibleEvent {
+impl Default for AnalyticsEvent {
     fn default() -> Self {
         Self {
             name: "pageview".to_string(),
@@ -68,68 +103,61 @@ impl Default for PlausibleEvent {
     }
 }
 
-pub async fn report_plausible_event(
-    state: AppState,
-    mut event: PlausibleEvent,
-    metadata: PlausibleMetadata,
-) {
-    let plausible_endpoint = get_env("PLAUSIBLE_ENDPOINT").unwrap_or("".to_string());
-    if plausible_endpoint.is_empty() {
-        return;
-    }
-    let plausible_domain = get_env("PLAUSIBLE_DOMAIN").unwrap_or("".to_string());
-    if plausible_domain.is_empty() {
-        return;
+#[cfg(test)]
+impl AppState {
+    /// State with the built-in theme, no signing and no disk cache, reporting to `analytics`.
+    pub(crate) fn for_tests(analytics: Arc<dyn analytics::AnalyticsSink>) -> Self {
+        let metrics = Arc::new(metrics::Metrics::new());
+        let upstream = Arc::new(upstream::Upstream::from_env(metrics.clone()));
+        let restricted = |hosts: fetch::HostAllowlist| Arc::new(upstream.restricted(hosts));
+        let bandcamp_hosts = || {
+            fetch::HostAllowlist::from_env(
+                "BANDCAMP_ALLOWED_HOSTS",
+                &routes::music_thumb::DEFAULT_BANDCAMP_HOSTS,
+            )
+        };
+        AppState {
+            join_handle: Arc::new(Mutex::new(None)),
+            signing_secret: None,
+            themes: Arc::new(theme::ThemeRegistry::load(None)),
+            i18n: Arc::new(i18n::Catalog::load()),
+            render_cache: Arc::new(cache::RenderCache::new(16, None)),
+            disk_cache: None,
+            icon_upstream: restricted(fetch::HostAllowlist::from_env(
+                "ICON_ALLOWED_HOSTS",
+                &routes::naotimes_og::DEFAULT_ICON_HOSTS,
+            )),
+            cover_upstream: restricted(fetch::HostAllowlist::from_env(
+                "COVER_ALLOWED_HOSTS",
+                &routes::project_card::DEFAULT_COVER_HOSTS,
+            )),
+            bandcamp_upstream: restricted(bandcamp_hosts()),
+            bandcamp_image_upstream: restricted(
+                bandcamp_hosts().with_host(routes::music_thumb::BANDCAMP_IMAGE_HOST),
+            ),
+            upstream,
+            rate_limiter: Arc::new(rate_limit::RateLimiter::from_env()),
+            metrics,
+            analytics,
+        }
     }
-    let mut lock = state.join_handle.lock().await;
-    *lock = Some(tokio::spawn(async move {
-        debug!("Reporting plausible event: {:?}", event);
-        debug!("Metadata: {:?}", &metadata);
-        event.domain = Some(plausible_domain);
-
-        let client = reqwest::ClientBuilder::new()
-            .user_agent(metadata.user_agent)
-            .build()
-            .unwrap();
-        let mut headers = HeaderMap::new();
-
-        let real_ip: Vec<String> = metadata
-            .ip_address
-            .iter()
-            .filter_map(|&ip| {
-                if is_private_ip(ip) {
-                    None
-                } else {
-                    Some(ip.to_string())
-                }
-            })
-            .collect();
-
-        let merged_real_ip = real_ip.join(", ");
-
-        headers.insert("X-Forwarded-For", merged_real_ip.parse().unwrap());
-        headers.insert("Content-Type", "application/json".parse().unwrap());
-
-        let body = serde_json::to_string(&event).unwrap();
-
-        debug!("Sending plausible event: {} // {:?}", body, headers);
-        // post
-        let _ = client
-            .post(format!("{}/api/event", plausible_endpoint))
-            .body(body)
-            .headers(headers)
-            .send()
-            .await
-            .unwrap();
-        debug!("Sent plausible event: {:?}", event);
-    }))
 }
 
 #[tokio::main]
 async fn main() {
-    let state = AppState {
-        join_handle: Arc::new(Mutex::new(None)),
-    };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("sign") {
+        if let Err(err) = signing::run_cli(&args[1..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let signing_secret = get_env("OG_SIGNING_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .map(Arc::from);
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
@@ -139,30 +167,156 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let app = Router::new()
+    let themes_dir = get_env("THEMES_DIR").unwrap_or("themes".to_string());
+    let themes = theme::ThemeRegistry::load(Some(themes_dir.into()));
+    let render_cache_size = get_env("RENDER_CACHE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(256);
+    let disk_cache = disk_cache::DiskCache::from_env().map(Arc::new);
+    let render_cache = Arc::new(cache::RenderCache::new(
+        render_cache_size,
+        disk_cache.clone(),
+    ));
+    let metrics_config = metrics::MetricsConfig::from_env();
+    let metrics = Arc::new(metrics::Metrics::new());
+    metrics.register_cache(render_cache.clone());
+    let upstream = Arc::new(upstream::Upstream::from_env(metrics.clone()));
+    let state = AppState {
+        join_handle: Arc::new(Mutex::new(None)),
+        signing_secret,
+        themes: Arc::new(themes),
+        i18n: Arc::new(i18n::Catalog::load()),
+        render_cache,
+        disk_cache,
+        icon_upstream: Arc::new(upstream.restricted(fetch::HostAllowlist::from_env(
+            "ICON_ALLOWED_HOSTS",
+            &routes::naotimes_og::DEFAULT_ICON_HOSTS,
+        ))),
+        cover_upstream: Arc::new(upstream.restricted(fetch::HostAllowlist::from_env(
+            "COVER_ALLOWED_HOSTS",
+            &routes::project_card::DEFAULT_COVER_HOSTS,
+        ))),
+        bandcamp_upstream: Arc::new(upstream.restricted(fetch::HostAllowlist::from_env(
+            "BANDCAMP_ALLOWED_HOSTS",
+            &routes::music_thumb::DEFAULT_BANDCAMP_HOSTS,
+        ))),
+        bandcamp_image_upstream: Arc::new(
+            upstream.restricted(
+                fetch::HostAllowlist::from_env(
+                    "BANDCAMP_ALLOWED_HOSTS",
+                    &routes::music_thumb::DEFAULT_BANDCAMP_HOSTS,
+                )
+                .with_host(routes::music_thumb::BANDCAMP_IMAGE_HOST),
+            ),
+        ),
+        analytics: analytics::from_env(upstream.clone()),
+        upstream,
+        rate_limiter: Arc::new(rate_limit::RateLimiter::from_env()),
+        metrics,
+    };
+
+    let mut app = Router::new()
         .route("/", get(index))
         .route("/large", get(routes::naotimes_og::handle_og_image_request))
+        .route("/project", get(routes::project_card::handle_project_card))
         .route("/_/health", get(|| async { "ok" }))
+        .route("/_/cache", get(handle_cache_stats))
         .route(
             "/music/bandcamp",
             get(routes::music_thumb::handle_bandcamp_thumb),
         )
+        .route(
+            "/music/bandcamp/meta",
+            get(routes::music_meta::handle_bandcamp_meta),
+        )
+        .route(
+            "/music/bandcamp/palette",
+            get(routes::music_thumb::handle_bandcamp_palette),
+        )
         .route(
             "/music/soundcloud/:artist/:title",
             get(routes::music_thumb::handle_soundcloud_thumb),
         )
+        .route(
+            "/music/soundcloud/:artist/:title/meta",
+            get(routes::music_meta::handle_soundcloud_meta),
+        )
+        .route(
+            "/music/soundcloud/:artist/:title/palette",
+            get(routes::music_thumb::handle_soundcloud_palette),
+        )
+        .route(
+            "/music/spotify/:kind/:id",
+            get(routes::music_thumb::handle_spotify_thumb),
+        )
+        .route(
+            "/music/spotify/:kind/:id/palette",
+            get(routes::music_thumb::handle_spotify_palette),
+        )
+        .route(
+            "/music/apple/:storefront/:id",
+            get(routes::music_thumb::handle_apple_music_thumb),
+        )
+        .route(
+            "/music/apple/:storefront/:id/palette",
+            get(routes::music_thumb::handle_apple_music_palette),
+        )
+        .route(
+            "/music/nowplaying",
+            get(routes::music_nowplaying::handle_nowplaying_card),
+        )
+        .route(
+            "/music/resolve",
+            get(routes::music_resolve::handle_music_resolve),
+        )
         .route(
             "/music/ytm/:id",
             get(routes::music_thumb::handle_youtube_music_thumb),
         )
+        .route(
+            "/music/ytm/:id/meta",
+            get(routes::music_meta::handle_youtube_music_meta),
+        )
+        .route(
+            "/music/ytm/:id/palette",
+            get(routes::music_thumb::handle_youtube_music_palette),
+        );
+    if metrics_config.enabled && metrics_config.port.is_none() {
+        app = app.route("/metrics", get(metrics::handle_metrics));
+    }
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::rate_limit,
+        ))
+        .layer(middleware::from_fn(prelude::json_errors))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_requests,
+        ))
         .layer(TraceLayer::new_for_http())
         .layer(CorsLayer::new().allow_origin(Any))
-        .with_state(state);
+        .with_state(state.clone());
     let app = app.fallback(handle_404);
 
     let host_at = get_env("HOST").unwrap_or("127.0.0.1".to_string());
     let port_at = get_env("PORT").unwrap_or("12460".to_string());
 
+    if let (true, Some(port)) = (metrics_config.enabled, metrics_config.port) {
+        let metrics_app = Router::new()
+            .route("/metrics", get(metrics::handle_metrics))
+            .with_state(state);
+        let listener = TcpListener::bind(format!("{}:{}", host_at, port))
+            .await
+            .unwrap();
+        tracing::info!(
+            "📈 Metrics at: http://{}/metrics",
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(async move { axum::serve(listener, metrics_app).await.unwrap() });
+    }
+
     // run it
     let listener = TcpListener::bind(format!("{}:{}", host_at, port_at))
         .await
@@ -171,13 +325,23 @@ async fn main() {
         "🚀 Fast serving at: http://{}",
         listener.local_addr().unwrap()
     );
-    axum::serve(listener, app).await.unwrap();
+    // The peer address identifies clients for rate limiting when no proxy headers are sent.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
 async fn index() -> &'static str {
     "</Mutex> Made for naoTimes by @noaione</>"
 }
 
+async fn handle_cache_stats(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.render_cache.stats())
+}
+
 async fn handle_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, Html("<h2>404 Not Found</h2>"))
 }
@@ -0,0 +1,199 @@
+/// Prometheus metrics, exposed at `/metrics` when `METRICS_ENABLED` is set
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use tracing::warn;
+
+use crate::{cache::RenderCache, env::get_env, AppState};
+
+pub struct Metrics {
+    registry: Registry,
+    /// Requests by route, method and status.
+    pub requests: IntCounterVec,
+    /// Request latency by route.
+    pub request_duration: HistogramVec,
+    /// Time spent drawing a card, before encoding, by card.
+    pub render_duration: HistogramVec,
+    /// Upstream requests by provider and status, `error` when no response came back.
+    pub upstream_requests: IntCounterVec,
+    /// Upstream latency by provider, one observation per attempt.
+    pub upstream_duration: HistogramVec,
+    /// Analytics events by result, `success` or `failure`.
+    pub analytics_events: IntCounterVec,
+}
+
+/// Reads the hit and miss counters of the render and disk caches at scrape time.
+struct CacheCollector {
+    render_cache: Arc<RenderCache>,
+    desc: Desc,
+}
+
+impl CacheCollector {
+    fn counters() -> IntCounterVec {
+        IntCounterVec::new(
+            Opts::new("cache_requests_total", "Cache lookups by cache and result"),
+            &["cache", "result"],
+        )
+        .unwrap()
+    }
+}
+
+impl Collector for CacheCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let stats = self.render_cache.stats();
+        let counters = Self::counters();
+        let mut counts = vec![("render", stats.hits, stats.misses)];
+        if let Some(disk) = stats.disk {
+            counts.push(("disk", disk.hits, disk.misses));
+        }
+        for (cache, hits, misses) in counts {
+            counters.with_label_values(&[cache, "hit"]).inc_by(hits);
+            counters.with_label_values(&[cache, "miss"]).inc_by(misses);
+        }
+        counters.collect()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("naotimes_og".to_string()), None).unwrap();
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+            &["route"],
+        )
+        .unwrap();
+        let render_duration = HistogramVec::new(
+            HistogramOpts::new("render_duration_seconds", "Card drawing time"),
+            &["card"],
+        )
+        .unwrap();
+        let upstream_requests = IntCounterVec::new(
+            Opts::new("upstream_requests_total", "Upstream requests by status"),
+            &["provider", "status"],
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new("upstream_request_duration_seconds", "Upstream latency"),
+            &["provider"],
+        )
+        .unwrap();
+        let analytics_events = IntCounterVec::new(
+            Opts::new("analytics_events_total", "Analytics events sent by result"),
+            &["result"],
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn Collector>,
+            Box::new(request_duration.clone()),
+            Box::new(render_duration.clone()),
+            Box::new(upstream_requests.clone()),
+            Box::new(upstream_duration.clone()),
+            Box::new(analytics_events.clone()),
+        ] {
+            registry.register(collector).unwrap();
+        }
+
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            render_duration,
+            upstream_requests,
+            upstream_duration,
+            analytics_events,
+        }
+    }
+
+    /// Include the cache counters of `render_cache` in every scrape.
+    pub fn register_cache(&self, render_cache: Arc<RenderCache>) {
+        let desc = CacheCollector::counters().desc()[0].clone();
+        let collector = CacheCollector { render_cache, desc };
+        self.registry.register(Box::new(collector)).unwrap();
+    }
+
+    /// Everything registered, in the Prometheus text format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            warn!("Failed to encode metrics: {}", err);
+        }
+        buffer
+    }
+}
+
+/// Whether `/metrics` is served, and on which port when it is not the main one.
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub port: Option<u16>,
+}
+
+impl MetricsConfig {
+    pub fn from_env() -> Self {
+        MetricsConfig {
+            enabled: get_env("METRICS_ENABLED")
+                .is_ok_and(|enabled| matches!(enabled.as_str(), "1" | "true")),
+            port: get_env("METRICS_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok()),
+        }
+    }
+}
+
+/// Count every request and its latency by matched route.
+pub async fn track_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    state
+        .metrics
+        .request_duration
+        .with_label_values(&[&route])
+        .observe(start.elapsed().as_secs_f64());
+    state
+        .metrics
+        .requests
+        .with_label_values(&[&route, &method, response.status().as_str()])
+        .inc();
+    response
+}
+
+pub async fn handle_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(
+            header::CONTENT_TYPE,
+            TextEncoder::new().format_type().to_string(),
+        )],
+        state.metrics.encode(),
+    )
+}
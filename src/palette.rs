@@ -0,0 +1,198 @@
+/// Color palette extraction, used to match Discord embed colors to images
+///
+/// Colors are found with median cut on a downscaled copy of the image: the pixels are
+/// repeatedly split at the median of their widest channel, and every final box becomes a
+/// swatch with its average color.
+use image::{imageops::FilterType, RgbaImage};
+use serde::Serialize;
+
+/// Images are downscaled to at most this size before sampling.
+const SAMPLE_SIZE: u32 = 64;
+const MAX_SWATCHES: usize = 8;
+/// Swatches smaller than this share of the pixels are never vibrant or muted.
+const MIN_SHARE: f32 = 0.01;
+/// Swatches closer than this (squared RGB distance) are merged into one.
+const MERGE_DISTANCE: u32 = 3 * 12 * 12;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Swatch {
+    pub color: String,
+    /// Share of the sampled pixels, from 0 to 1.
+    pub population: f32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Palette {
+    pub dominant: String,
+    pub vibrant: Option<String>,
+    pub muted: Option<String>,
+    /// Black or white, whichever reads better on top of the dominant color.
+    pub text: String,
+    pub swatches: Vec<Swatch>,
+}
+
+fn hex(color: [u8; 3]) -> String {
+    format!("#{}", hex::encode(color))
+}
+
+/// Saturation and lightness in HSL, both from 0 to 1.
+fn saturation_lightness(color: [u8; 3]) -> (f32, f32) {
+    let [r, g, b] = color.map(|c| c as f32 / 255.);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.;
+    if max == min {
+        return (0., lightness);
+    }
+    let saturation = (max - min) / (1. - (2. * lightness - 1.).abs());
+    (saturation, lightness)
+}
+
+/// WCAG relative luminance.
+fn luminance(color: [u8; 3]) -> f32 {
+    let [r, g, b] = color.map(|c| {
+        let c = c as f32 / 255.;
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Pick black or white text, whichever has the higher contrast ratio.
+fn text_color(background: [u8; 3]) -> [u8; 3] {
+    let luminance = luminance(background);
+    let white = 1.05 / (luminance + 0.05);
+    let black = (luminance + 0.05) / 0.05;
+    if white >= black {
+        [255, 255, 255]
+    } else {
+        [0, 0, 0]
+    }
+}
+
+/// Widest channel of a set of pixels and its range.
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), pixel| {
+                (min.min(pixel[channel]), max.max(pixel[channel]))
+            });
+            (channel, max.saturating_sub(min))
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+fn median_cut(pixels: Vec<[u8; 3]>) -> Vec<Vec<[u8; 3]>> {
+    let mut boxes = vec![pixels];
+    while boxes.len() < MAX_SWATCHES {
+        // Split the box with the widest color range, stop once every box is a single color.
+        let (index, channel, range) = boxes
+            .iter()
+            .enumerate()
+            .map(|(index, pixels)| {
+                let (channel, range) = widest_channel(pixels);
+                (index, channel, range)
+            })
+            .max_by_key(|(_, _, range)| *range)
+            .unwrap_or((0, 0, 0));
+        if range == 0 {
+            break;
+        }
+        let mut pixels = boxes.swap_remove(index);
+        pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = pixels.split_off(pixels.len() / 2);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+    boxes
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (*a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    for pixel in pixels {
+        for (total, value) in sum.iter_mut().zip(pixel) {
+            *total += *value as u64;
+        }
+    }
+    sum.map(|total| (total / pixels.len().max(1) as u64) as u8)
+}
+
+/// Extract the palette of an image, ignoring mostly transparent pixels.
+pub fn extract(image: &RgbaImage) -> Palette {
+    let sample = if image.width() > SAMPLE_SIZE || image.height() > SAMPLE_SIZE {
+        image::DynamicImage::ImageRgba8(image.clone())
+            .resize(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle)
+            .into_rgba8()
+    } else {
+        image.clone()
+    };
+    let pixels: Vec<[u8; 3]> = sample
+        .pixels()
+        .filter(|pixel| pixel.0[3] >= 128)
+        .map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]])
+        .collect();
+    let total = pixels.len().max(1) as f32;
+
+    let mut boxes: Vec<([u8; 3], f32)> = median_cut(pixels)
+        .iter()
+        .filter(|pixels| !pixels.is_empty())
+        .map(|pixels| (average(pixels), pixels.len() as f32 / total))
+        .collect();
+    boxes.sort_by(|a, b| b.1.total_cmp(&a.1));
+    // Large flat areas end up split into several boxes of the same color.
+    let mut swatches: Vec<([u8; 3], f32)> = Vec::new();
+    for (color, share) in boxes {
+        match swatches
+            .iter_mut()
+            .find(|(merged, _)| distance(*merged, color) < MERGE_DISTANCE)
+        {
+            Some((_, merged_share)) => *merged_share += share,
+            None => swatches.push((color, share)),
+        }
+    }
+
+    let dominant = swatches.first().map(|(color, _)| *color).unwrap_or([0; 3]);
+    let candidates = || {
+        swatches
+            .iter()
+            .filter(|(_, share)| *share >= MIN_SHARE)
+            .map(|(color, share)| (*color, *share, saturation_lightness(*color)))
+    };
+    let vibrant = candidates()
+        .filter(|(_, _, (saturation, lightness))| {
+            *saturation >= 0.35 && (0.25..=0.8).contains(lightness)
+        })
+        .max_by(|a, b| (a.2 .0 * a.1.sqrt()).total_cmp(&(b.2 .0 * b.1.sqrt())))
+        .map(|(color, _, _)| hex(color));
+    let muted = candidates()
+        .filter(|(_, _, (saturation, lightness))| {
+            *saturation < 0.35 && (0.2..=0.7).contains(lightness)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(color, _, _)| hex(color));
+
+    Palette {
+        dominant: hex(dominant),
+        vibrant,
+        muted,
+        text: hex(text_color(dominant)),
+        swatches: swatches
+            .into_iter()
+            .map(|(color, population)| Swatch {
+                color: hex(color),
+                population,
+            })
+            .collect(),
+    }
+}
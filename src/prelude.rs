@@ -1,35 +1,145 @@
 use std::net::IpAddr;
 
 use axum::{
+    extract::Request,
     http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
+    Json,
 };
 use reqwest::header::GetAll;
 
-use crate::PlausibleMetadata;
+use crate::VisitorMetadata;
 
-struct AppError(anyhow::Error);
+/// Errors of route handlers, sent as plain text or as JSON to clients accepting `application/json`.
+#[derive(Debug, Clone)]
+pub enum AppError {
+    /// Invalid input from the client, 400.
+    BadRequest(String),
+    /// Missing or invalid signature, 403.
+    Forbidden(String),
+    /// The requested page or item does not exist upstream, 404.
+    NotFound(String),
+    /// The client ran out of its rate limit budget, 429.
+    TooManyRequests(String),
+    /// The upstream failed or answered something we could not use, 502.
+    Upstream(String),
+    /// The upstream did not answer in time, 504.
+    Timeout(String),
+    /// Rendering or encoding failed on our side, 500.
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::TooManyRequests(_) => "rate_limited",
+            AppError::Upstream(_) => "upstream_error",
+            AppError::Timeout(_) => "upstream_timeout",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::BadRequest(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::TooManyRequests(message)
+            | AppError::Upstream(message)
+            | AppError::Timeout(message)
+            | AppError::Internal(message) => message,
+        }
+    }
+
+    /// A failed upstream request, `message` is shown to the client unless it timed out or
+    /// the URL was refused.
+    pub fn upstream(err: &reqwest::Error, message: &str) -> Self {
+        if let Some(blocked) = crate::fetch::find_blocked(err) {
+            tracing::warn!("Refused upstream request: {}", blocked);
+            return AppError::BadRequest(blocked.to_string());
+        }
+        tracing::error!("{}: {}", message, err);
+        if err.is_timeout() {
+            AppError::Timeout("Upstream timed out".to_string())
+        } else {
+            AppError::Upstream(message.to_string())
+        }
+    }
+
+    /// Like [`AppError::upstream`] for errors that may wrap a request error.
+    pub fn upstream_any(err: &anyhow::Error, message: &str) -> Self {
+        match err.downcast_ref::<reqwest::Error>() {
+            Some(err) => AppError::upstream(err, message),
+            None => {
+                tracing::error!("{}: {}", message, err);
+                AppError::Upstream(message.to_string())
+            }
+        }
+    }
+
+    /// A failure on our side, logged with its cause while the client only sees `message`.
+    pub fn internal(err: impl std::fmt::Display, message: &str) -> Self {
+        tracing::error!("{}: {}", message, err);
+        AppError::Internal(message.to_string())
+    }
+}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        let mut response = (self.status(), self.message().to_string()).into_response();
+        // Picked up by `json_errors` when the client wants JSON.
+        response.extensions_mut().insert(self);
+        response
     }
 }
 
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+/// Rewrite [`AppError`] responses as JSON for clients accepting `application/json`.
+pub async fn json_errors(request: Request, next: Next) -> Response {
+    let wants_json = request
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/json"));
+    let mut response = next.run(request).await;
+    if !wants_json {
+        return response;
+    }
+    match response.extensions_mut().remove::<AppError>() {
+        Some(err) => {
+            let body = serde_json::json!({
+                "error": err.code(),
+                "message": err.message(),
+            });
+            let mut json = (err.status(), Json(body)).into_response();
+            for (name, value) in response.headers() {
+                if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+                    json.headers_mut().append(name, value.clone());
+                }
+            }
+            json
+        }
+        None => response,
     }
 }
 
-impl From<HeaderMap> for PlausibleMetadata {
+impl From<HeaderMap> for VisitorMetadata {
     fn from(val: HeaderMap) -> Self {
         let user_agent = val
             .get(header::USER_AGENT)
@@ -52,7 +162,7 @@ impl From<HeaderMap> for PlausibleMetadata {
         ip_address.extend(forwarded);
         ip_address.extend(x_real_ip);
 
-        PlausibleMetadata {
+        VisitorMetadata {
             user_agent,
             ip_address,
         }
@@ -65,10 +175,7 @@ fn parse_specific_headers(headers: &GetAll<HeaderValue>) -> Vec<IpAddr> {
         .filter_map(|v| {
             // parse into IpAddr
             match v.to_str() {
-                Ok(v) => match v.parse() {
-                    Ok(v) => Some(v),
-                    Err(_) => None,
-                },
+                Ok(v) => v.parse().ok(),
                 Err(_) => None,
             }
         })
@@ -78,25 +185,29 @@ fn parse_specific_headers(headers: &GetAll<HeaderValue>) -> Vec<IpAddr> {
 pub fn is_private_ip(ip: IpAddr) -> bool {
     match ip {
         IpAddr::V4(ipv4) => {
-            if ipv4.is_private()
+            let [first, second, ..] = ipv4.octets();
+            ipv4.is_private()
                 || ipv4.is_loopback()
                 || ipv4.is_link_local()
                 || ipv4.is_unspecified()
                 || ipv4.is_broadcast()
                 || ipv4.is_documentation()
                 || ipv4.is_multicast()
-            {
-                true
-            } else {
-                false
-            }
+                // "This network" 0.0.0.0/8 and carrier-grade NAT 100.64.0.0/10
+                || first == 0
+                || (first == 100 && (second & 0xc0) == 64)
         }
-        IpAddr::V6(ipv6) => {
-            if ipv6.is_loopback() || ipv6.is_multicast() || ipv6.is_unspecified() {
-                true
-            } else {
-                false
+        IpAddr::V6(ipv6) => match ipv6.to_ipv4_mapped() {
+            Some(ipv4) => is_private_ip(IpAddr::V4(ipv4)),
+            None => {
+                let first = ipv6.segments()[0];
+                ipv6.is_loopback()
+                    || ipv6.is_multicast()
+                    || ipv6.is_unspecified()
+                    // Unique local fc00::/7 and link-local fe80::/10
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80
             }
-        }
+        },
     }
 }
@@ -0,0 +1,466 @@
+/// Per-client rate limiting with token buckets
+///
+/// Clients are identified by their peer address, or by the address a proxy from
+/// `RATE_LIMIT_TRUSTED_PROXIES` forwarded. Requests that render or process images draw
+/// from a smaller "expensive" budget than the rest.
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use lru::LruCache;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::{env::get_env, prelude::AppError, routes::music_resolve::MusicLink, AppState};
+
+/// Header carrying an API key from `RATE_LIMIT_API_KEYS`.
+static API_KEY_HEADER: &str = "x-api-key";
+/// Buckets kept before the least recently used one is dropped.
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    /// Renders and image processing.
+    Expensive,
+    /// Redirects, cached lookups and metadata.
+    Cheap,
+}
+
+/// The query parameters deciding how much work a music route does.
+#[derive(Deserialize, Debug, Default)]
+struct WorkQuery {
+    mode: Option<String>,
+    size: Option<String>,
+    fit: Option<String>,
+    square: Option<String>,
+    url: Option<String>,
+}
+
+impl Budget {
+    /// Which budget a request draws from, `None` for paths that are never limited.
+    ///
+    /// Music routes are expensive whenever they download the image: palettes, proxies,
+    /// resizing and YouTube thumbnails, which are always processed.
+    fn of(path: &str, query: Option<&str>) -> Option<Budget> {
+        if path.starts_with("/_/") || path == "/metrics" {
+            return None;
+        }
+        let expensive = match path {
+            "/large" | "/project" | "/music/nowplaying" => true,
+            path if path.starts_with("/music/") && !path.ends_with("/meta") => {
+                let query: WorkQuery = query
+                    .and_then(|query| serde_qs::from_str(query).ok())
+                    .unwrap_or_default();
+                let proxied = query
+                    .mode
+                    .is_some_and(|mode| !mode.eq_ignore_ascii_case("redirect"));
+                // Apple Music serves artwork at any size, resizing it costs us nothing.
+                let resized = query.size.is_some() && !path.starts_with("/music/apple/");
+                let youtube = path.starts_with("/music/ytm/")
+                    || (path == "/music/resolve"
+                        && query.url.is_some_and(|url| {
+                            matches!(MusicLink::parse(&url), Ok(MusicLink::YouTube { .. }))
+                        }));
+                path.ends_with("/palette")
+                    || proxied
+                    || resized
+                    || query.fit.is_some()
+                    || query.square.is_some()
+                    || youtube
+            }
+            _ => false,
+        };
+        Some(match expensive {
+            true => Budget::Expensive,
+            false => Budget::Cheap,
+        })
+    }
+}
+
+/// Refill rate and size of a bucket.
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl Limit {
+    /// Read `<prefix>_PER_MIN` and `<prefix>_BURST`, `None` when the budget is unlimited.
+    fn from_env(prefix: &str, per_minute: u64, burst: u64) -> Option<Limit> {
+        let number = |key: &str, default: u64| {
+            get_env(&format!("{}_{}", prefix, key))
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let per_minute = number("PER_MIN", per_minute);
+        if per_minute == 0 {
+            return None;
+        }
+        Some(Limit {
+            per_second: per_minute as f64 / 60.,
+            burst: number("BURST", burst).max(1) as f64,
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// An IP address or CIDR range like `10.0.0.0/8`.
+#[derive(Debug)]
+struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    fn parse(range: &str) -> Option<IpRange> {
+        let (addr, prefix) = match range.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (range.parse().ok()?, None),
+        };
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(IpRange { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (range, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                (u32::from(range) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => (u128::from(range), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix;
+        shift >= bits || (range >> shift) == (ip >> shift)
+    }
+}
+
+pub struct RateLimiter {
+    expensive: Option<Limit>,
+    cheap: Option<Limit>,
+    api_keys: Vec<String>,
+    exempt: Vec<IpRange>,
+    /// Proxies whose forwarded client addresses are believed.
+    trusted_proxies: Vec<IpRange>,
+    buckets: Mutex<LruCache<(Budget, IpAddr), Bucket>>,
+}
+
+fn env_ranges(key: &str) -> Vec<IpRange> {
+    env_list(key)
+        .iter()
+        .filter_map(|range| {
+            let parsed = IpRange::parse(range);
+            if parsed.is_none() {
+                warn!("Ignoring invalid address in {}: `{}`", key, range);
+            }
+            parsed
+        })
+        .collect()
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    get_env(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+impl RateLimiter {
+    /// Read the budgets and exemptions from the `RATE_LIMIT_*` variables, see `.env.example`.
+    pub fn from_env() -> Self {
+        let limiter = RateLimiter {
+            expensive: Limit::from_env("RATE_LIMIT_EXPENSIVE", 30, 10),
+            cheap: Limit::from_env("RATE_LIMIT_CHEAP", 300, 60),
+            api_keys: env_list("RATE_LIMIT_API_KEYS"),
+            exempt: env_ranges("RATE_LIMIT_EXEMPT_IPS"),
+            trusted_proxies: env_ranges("RATE_LIMIT_TRUSTED_PROXIES"),
+            buckets: Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_BUCKETS).expect("MAX_BUCKETS is not zero"),
+            )),
+        };
+        info!(
+            "Rate limits: expensive {:?}, cheap {:?}",
+            limiter.expensive, limiter.cheap
+        );
+        limiter
+    }
+
+    fn limit(&self, budget: Budget) -> Option<Limit> {
+        match budget {
+            Budget::Expensive => self.expensive,
+            Budget::Cheap => self.cheap,
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+
+    /// The address of the client behind `peer`.
+    ///
+    /// Forwarding headers are only read when `peer` is a trusted proxy. `X-Forwarded-For` is
+    /// read from the right, skipping trusted proxies, since clients can prepend anything.
+    fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let values = |name: &str| -> Vec<IpAddr> {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .filter_map(|ip| ip.trim().parse().ok())
+                .collect()
+        };
+        let forwarded = values("x-forwarded-for");
+        if let Some(ip) = forwarded.iter().rev().find(|ip| !self.is_trusted(**ip)) {
+            return *ip;
+        }
+        ["x-real-ip", "cf-connecting-ip", "cf-connecting-ipv6"]
+            .iter()
+            .find_map(|name| values(name).last().copied())
+            .or(forwarded.first().copied())
+            .unwrap_or(peer)
+    }
+
+    fn is_exempt(&self, headers: &HeaderMap, ip: IpAddr) -> bool {
+        let api_key = headers
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok());
+        api_key.is_some_and(|key| self.api_keys.iter().any(|allowed| allowed == key))
+            || self.exempt.iter().any(|range| range.contains(ip))
+    }
+
+    /// Take a token from the bucket of `ip`, or return how long until one is available.
+    fn acquire(&self, budget: Budget, ip: IpAddr) -> Result<(), Duration> {
+        let limit = match self.limit(budget) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // When full, the bucket updated longest ago is dropped.
+        let bucket = buckets.get_or_insert_mut((budget, ip), || Bucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1. - bucket.tokens) / limit.per_second,
+            ))
+        }
+    }
+}
+
+/// Answer 429 with `Retry-After` once a client runs out of tokens.
+pub async fn rate_limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let budget = match Budget::of(request.uri().path(), request.uri().query()) {
+        Some(budget) => budget,
+        None => return next.run(request).await,
+    };
+    let peer = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip(),
+        None => return next.run(request).await,
+    };
+    let ip = state.rate_limiter.client_ip(request.headers(), peer);
+    if state.rate_limiter.is_exempt(request.headers(), ip) {
+        return next.run(request).await;
+    }
+
+    match state.rate_limiter.acquire(budget, ip) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.) as u64;
+            debug!(
+                "Rate limited {} on {:?} budget, retry in {}s",
+                ip, budget, retry_after
+            );
+            let mut response =
+                AppError::TooManyRequests("Too many requests".to_string()).into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn limiter(trusted_proxies: &[&str]) -> RateLimiter {
+        RateLimiter {
+            expensive: Some(Limit {
+                per_second: 1.,
+                burst: 2.,
+            }),
+            cheap: None,
+            api_keys: vec![],
+            exempt: vec![],
+            trusted_proxies: trusted_proxies
+                .iter()
+                .filter_map(|range| IpRange::parse(range))
+                .collect(),
+            buckets: Mutex::new(LruCache::new(NonZeroUsize::new(2).unwrap())),
+        }
+    }
+
+    fn forwarded(entries: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn client_ip_ignores_headers_from_untrusted_peers() {
+        let limiter = limiter(&["10.0.0.0/8"]);
+        let headers = forwarded(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("cf-connecting-ip", "198.51.100.2"),
+        ]);
+        assert_eq!(
+            limiter.client_ip(&headers, ip("203.0.113.9")),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn client_ip_takes_the_rightmost_untrusted_forwarded_hop() {
+        let limiter = limiter(&["10.0.0.0/8"]);
+        let headers = forwarded(&[
+            ("x-forwarded-for", "192.0.2.66, 198.51.100.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(
+            limiter.client_ip(&headers, ip("10.0.0.1")),
+            ip("198.51.100.1")
+        );
+
+        let headers = forwarded(&[("x-real-ip", "198.51.100.3")]);
+        assert_eq!(
+            limiter.client_ip(&headers, ip("10.0.0.1")),
+            ip("198.51.100.3")
+        );
+        assert_eq!(
+            limiter.client_ip(&HeaderMap::new(), ip("10.0.0.1")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn buckets_are_capped() {
+        let limiter = limiter(&[]);
+        for last in 1..=5 {
+            let _ = limiter.acquire(Budget::Expensive, ip(&format!("192.0.2.{}", last)));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+
+        let client = ip("198.51.100.1");
+        assert!(limiter.acquire(Budget::Expensive, client).is_ok());
+        assert!(limiter.acquire(Budget::Expensive, client).is_ok());
+        assert!(limiter.acquire(Budget::Expensive, client).is_err());
+    }
+
+    #[test]
+    fn budget_follows_the_work_done() {
+        let budget = |path, query| Budget::of(path, query);
+        assert_eq!(budget("/_/health", None), None);
+        assert_eq!(budget("/large", None), Some(Budget::Expensive));
+        assert_eq!(budget("/music/ytm/abc", None), Some(Budget::Expensive));
+        assert_eq!(budget("/music/ytm/abc/meta", None), Some(Budget::Cheap));
+        assert_eq!(
+            budget("/music/spotify/track/abc", None),
+            Some(Budget::Cheap)
+        );
+        assert_eq!(
+            budget("/music/spotify/track/abc/palette", None),
+            Some(Budget::Expensive)
+        );
+        for query in [
+            "mode=proxy",
+            "mode=palette",
+            "size=300",
+            "fit=cover",
+            "square=true",
+        ] {
+            assert_eq!(
+                budget("/music/bandcamp", Some(query)),
+                Some(Budget::Expensive),
+                "{}",
+                query
+            );
+        }
+        assert_eq!(
+            budget("/music/bandcamp", Some("url=x&mode=redirect")),
+            Some(Budget::Cheap)
+        );
+        assert_eq!(
+            budget("/music/apple/jp/123", Some("size=600")),
+            Some(Budget::Cheap)
+        );
+        assert_eq!(
+            budget("/music/resolve", Some("url=https%3A%2F%2Fyoutu.be%2Fabc")),
+            Some(Budget::Expensive)
+        );
+    }
+
+    #[test]
+    fn ip_range_contains_addresses_inside_its_prefix() {
+        let range = IpRange::parse("10.1.0.0/16").unwrap();
+        assert!(range.contains(ip("10.1.0.1")));
+        assert!(range.contains(ip("10.1.255.255")));
+        assert!(!range.contains(ip("10.2.0.1")));
+        assert!(!range.contains(ip("::ffff:10.1.0.1")));
+
+        let range = IpRange::parse("2001:db8::/32").unwrap();
+        assert!(range.contains(ip("2001:db8::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn ip_range_edge_prefixes() {
+        let single = IpRange::parse("192.0.2.7").unwrap();
+        assert!(single.contains(ip("192.0.2.7")));
+        assert!(!single.contains(ip("192.0.2.8")));
+
+        let everything = IpRange::parse("0.0.0.0/0").unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+        assert!(!everything.contains(ip("::1")));
+
+        assert!(IpRange::parse("10.0.0.0/33").is_none());
+        assert!(IpRange::parse("not an ip").is_none());
+    }
+}
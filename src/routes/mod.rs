@@ -1,2 +1,6 @@
+pub mod music_meta;
+pub mod music_nowplaying;
+pub mod music_resolve;
 pub mod music_thumb;
 pub mod naotimes_og;
+pub mod project_card;
@@ -0,0 +1,321 @@
+/// Track metadata for the music player, scraped from the same pages as the thumbnails
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::info;
+use urlencoding::{decode, encode};
+
+use crate::{
+    analytics::report_event,
+    cache::RenderCache,
+    env::get_env,
+    prelude::AppError,
+    routes::music_thumb::{
+        body_failed, check_bandcamp_url, check_ytm_id, disk_get, disk_put, fetch_failed,
+        find_attribute, upstream_failed, BandcampRequest, SoundcloudRequest, YTMRequest,
+    },
+    upstream::Upstream,
+    AnalyticsEvent, AppState, VisitorMetadata,
+};
+
+/// Metadata JSON of tracks, albums and videos.
+const META_NAMESPACE: &str = "meta";
+
+#[derive(Serialize, Debug)]
+pub struct TrackMeta {
+    pub provider: &'static str,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Duration in seconds, the whole album for album pages.
+    pub duration: Option<f64>,
+    /// Canonical URL of the page.
+    pub url: Option<String>,
+    pub artwork_url: Option<String>,
+}
+
+type MetaResult = Result<TrackMeta, AppError>;
+
+/// Fetch a page, mapping a 404 to `not_found`.
+async fn fetch_page(upstream: &Upstream, url: &str, not_found: String) -> Result<String, AppError> {
+    let req = upstream.get(url).await.map_err(fetch_failed)?;
+    if req.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(AppError::NotFound(not_found));
+    }
+    if !req.status().is_success() {
+        return Err(upstream_failed());
+    }
+    upstream.text(req).await.map_err(body_failed)
+}
+
+/// Text of the first element matching `selector`, like [`find_attribute`].
+fn find_text(html: &str, selector: &str) -> Option<String> {
+    let parsed_html = scraper::Html::parse_document(html);
+    let selector = scraper::Selector::parse(selector).unwrap();
+    parsed_html
+        .select(&selector)
+        .next()
+        .map(|element| element.text().collect())
+}
+
+fn json_string(value: &Value, pointer: &str) -> Option<String> {
+    value
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+}
+
+/// Parse an ISO 8601 duration like `PT00H03M21S` into seconds.
+fn parse_iso_duration(duration: &str) -> Option<f64> {
+    let time = duration.strip_prefix("PT")?;
+    let mut seconds = 0.;
+    let mut number = String::new();
+    for c in time.chars() {
+        match c {
+            '0'..='9' | '.' => number.push(c),
+            'H' | 'M' | 'S' => {
+                let value: f64 = number.parse().ok()?;
+                number.clear();
+                seconds += value
+                    * match c {
+                        'H' => 3600.,
+                        'M' => 60.,
+                        _ => 1.,
+                    };
+            }
+            _ => return None,
+        }
+    }
+    Some(seconds)
+}
+
+fn bandcamp_meta(html: &str) -> Option<TrackMeta> {
+    let tralbum: Value = serde_json::from_str(&find_attribute(
+        html,
+        "script[data-tralbum]",
+        "data-tralbum",
+    )?)
+    .ok()?;
+    let embed: Value = find_attribute(html, "script[data-embed]", "data-embed")
+        .and_then(|embed| serde_json::from_str(&embed).ok())
+        .unwrap_or_default();
+
+    let is_album = json_string(&tralbum, "/item_type").as_deref() == Some("album");
+    let durations = tralbum
+        .pointer("/trackinfo")
+        .and_then(Value::as_array)
+        .map(|tracks| {
+            tracks
+                .iter()
+                .filter_map(|track| track.pointer("/duration").and_then(Value::as_f64))
+                .collect::<Vec<f64>>()
+        })
+        .unwrap_or_default();
+
+    let title = json_string(&tralbum, "/current/title");
+    Some(TrackMeta {
+        provider: "bandcamp",
+        artist: json_string(&tralbum, "/artist"),
+        album: if is_album {
+            title.clone()
+        } else {
+            json_string(&embed, "/album_title")
+        },
+        title,
+        duration: match is_album {
+            true if !durations.is_empty() => Some(durations.iter().sum()),
+            _ => durations.first().copied(),
+        },
+        url: json_string(&tralbum, "/url"),
+        artwork_url: find_attribute(html, r#"link[rel="image_src"]"#, "href"),
+    })
+}
+
+fn soundcloud_meta(html: &str) -> TrackMeta {
+    let meta = |property: &str| {
+        find_attribute(
+            html,
+            &format!(r#"meta[property="{}"]"#, property),
+            "content",
+        )
+    };
+    let ld: Value = find_text(html, r#"script[type="application/ld+json"]"#)
+        .and_then(|ld| serde_json::from_str(&ld).ok())
+        .unwrap_or_default();
+
+    TrackMeta {
+        provider: "soundcloud",
+        title: json_string(&ld, "/name").or_else(|| meta("og:title")),
+        artist: json_string(&ld, "/byArtist/name").or_else(|| meta("twitter:audio:artist_name")),
+        album: json_string(&ld, "/inAlbum/name"),
+        duration: json_string(&ld, "/duration").and_then(|duration| parse_iso_duration(&duration)),
+        url: meta("og:url"),
+        artwork_url: meta("og:image"),
+    }
+}
+
+/// Base URL of YouTube, overridable with `YOUTUBE_BASE_URL` to use a stand-in server.
+fn youtube_base_url() -> String {
+    get_env("YOUTUBE_BASE_URL")
+        .unwrap_or("https://www.youtube.com".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+async fn resolve_youtube_meta(upstream: &Upstream, id: &str) -> MetaResult {
+    let req = upstream
+        .get(format!(
+            "{}/oembed?format=json&url={}",
+            youtube_base_url(),
+            encode(&format!("https://www.youtube.com/watch?v={}", id))
+        ))
+        .await
+        .map_err(fetch_failed)?;
+    // oEmbed answers 400 for malformed IDs, 401 for private and 404 for missing videos.
+    if req.status().is_client_error() {
+        return Err(AppError::NotFound(format!(
+            "YouTube Music track not found: `{}`",
+            id
+        )));
+    }
+    if !req.status().is_success() {
+        return Err(upstream_failed());
+    }
+    let body = upstream.text(req).await.map_err(body_failed)?;
+    let oembed: Value = serde_json::from_str(&body).map_err(|_| upstream_failed())?;
+
+    Ok(TrackMeta {
+        provider: "youtube",
+        title: json_string(&oembed, "/title"),
+        // Auto-generated music channels are named `<artist> - Topic`.
+        artist: json_string(&oembed, "/author_name")
+            .map(|artist| artist.trim_end_matches(" - Topic").to_string()),
+        album: None,
+        duration: None,
+        url: Some(format!("https://music.youtube.com/watch?v={}", id)),
+        artwork_url: json_string(&oembed, "/thumbnail_url"),
+    })
+}
+
+/// Serve metadata from the disk cache or `resolve`, reporting the request to analytics.
+async fn serve_meta(
+    state: AppState,
+    cache_key: String,
+    event_url: String,
+    og_headers: HeaderMap,
+    resolve: impl std::future::Future<Output = MetaResult>,
+) -> Result<Response, AppError> {
+    let cached = disk_get(&state, META_NAMESPACE, &cache_key)
+        .await
+        .and_then(|data| String::from_utf8(data).ok());
+    let resolved = match cached {
+        Some(json) => Ok(json),
+        None => match resolve.await {
+            Ok(meta) => {
+                let json = serde_json::to_string(&meta).unwrap_or_default();
+                disk_put(&state, META_NAMESPACE, &cache_key, json.as_bytes()).await;
+                Ok(json)
+            }
+            Err(err) => Err(err),
+        },
+    };
+
+    let metadata: VisitorMetadata = og_headers.into();
+    let event = AnalyticsEvent::default()
+        .with_url(event_url)
+        .with_props(serde_json::json!({
+            "success": resolved.is_ok().to_string(),
+        }));
+    report_event(state, event, metadata).await;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+    headers.insert(
+        header::CACHE_CONTROL,
+        "public, max-age=7200".parse().unwrap(),
+    );
+    Ok((StatusCode::OK, headers, resolved?).into_response())
+}
+
+pub async fn handle_bandcamp_meta(
+    query: Query<BandcampRequest>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let decode_url = decode(&query.url)
+        .map_err(|_| AppError::BadRequest("Invalid URL".to_string()))?
+        .to_string();
+    info!("Processing bandcamp metadata: {}", decode_url);
+
+    let cache_key = RenderCache::key(&["bandcamp-meta", &decode_url]);
+    let event_url = format!("/music/bandcamp/meta?url={}", encode(&decode_url));
+    let upstream = state.bandcamp_upstream.clone();
+    let resolve = async {
+        check_bandcamp_url(&upstream, &decode_url)?;
+        let html = fetch_page(
+            &upstream,
+            &decode_url,
+            format!("Bandcamp not found: `{}`", decode_url),
+        )
+        .await?;
+        bandcamp_meta(&html).ok_or(AppError::NotFound("Failed to find metadata".to_string()))
+    };
+    serve_meta(state, cache_key, event_url, og_headers, resolve).await
+}
+
+pub async fn handle_soundcloud_meta(
+    request: Path<SoundcloudRequest>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    info!("Processing soundcloud metadata: {:?}", request);
+
+    let cache_key = RenderCache::key(&["soundcloud-meta", &request.artist, &request.title]);
+    let event_url = format!(
+        "/music/soundcloud/{}/{}/meta",
+        request.artist, request.title
+    );
+    let upstream = state.upstream.clone();
+    let resolve = async {
+        let html = fetch_page(
+            &upstream,
+            &format!(
+                "https://soundcloud.com/{}/{}",
+                request.artist, request.title
+            ),
+            format!(
+                "Soundcloud track not found: `/{}/{}`",
+                request.artist, request.title
+            ),
+        )
+        .await?;
+        Ok(soundcloud_meta(&html))
+    };
+    serve_meta(state, cache_key, event_url, og_headers, resolve).await
+}
+
+pub async fn handle_youtube_music_meta(
+    request: Path<YTMRequest>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    info!("Processing YouTube Music metadata: {:?}", request);
+    check_ytm_id(&request.id)?;
+
+    let cache_key = RenderCache::key(&["ytm-meta", &request.id]);
+    let event_url = format!("/music/ytm/{}/meta", request.id);
+    let upstream = state.upstream.clone();
+    serve_meta(
+        state,
+        cache_key,
+        event_url,
+        og_headers,
+        resolve_youtube_meta(&upstream, &request.id),
+    )
+    .await
+}
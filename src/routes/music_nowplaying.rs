@@ -0,0 +1,363 @@
+/// "Now playing" card for the music player
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::Response,
+};
+use fluent_bundle::FluentArgs;
+use image::{DynamicImage, RgbaImage};
+use og_image_writer::{img::ImageInputFormat, style, writer::OGImageWriter};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    cache::{self, Card, Prepared, RenderCache, RenderOutput},
+    encoding,
+    i18n::Localizer,
+    imaging, palette,
+    prelude::AppError,
+    routes::{music_resolve::MusicLink, music_thumb},
+    signing,
+    theme::{self, Theme},
+    AppState,
+};
+
+const CARD_WIDTH: u32 = 1280;
+const CARD_HEIGHT: u32 = 480;
+const PADDING: u32 = 60;
+const ARTWORK_SIZE: u32 = 360;
+const ARTWORK_GAP: u32 = 48;
+const PROGRESS_HEIGHT: u32 = 14;
+const TIME_FONT_SIZE: f32 = 24.;
+/// How much of the dominant artwork color is blended over the blurred background.
+const TINT_AMOUNT: f32 = 0.45;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NowPlayingRequest {
+    /// Link to the track on any provider supported by `/music/resolve`.
+    url: String,
+    title: String,
+    artist: Option<String>,
+    /// Name of who queued the track.
+    requester: Option<String>,
+    /// Playback position in seconds.
+    position: Option<f64>,
+    /// Track length in seconds, the progress bar is drawn when given.
+    duration: Option<f64>,
+    /// Theme name, see [`crate::theme`].
+    theme: Option<String>,
+    /// Card language, negotiated from `Accept-Language` when missing.
+    lang: Option<String>,
+    /// Output format, negotiated from the `Accept` header when missing.
+    format: Option<String>,
+    /// Quality of lossy output formats, 1-100.
+    quality: Option<u8>,
+    /// HMAC signature of the other parameters, see [`crate::signing`].
+    #[serde(skip_serializing)]
+    sig: Option<String>,
+}
+
+/// Format seconds as `m:ss`, or `h:mm:ss` from an hour on.
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.) as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+fn rgba(color: [u8; 4]) -> style::Rgba {
+    style::Rgba(color)
+}
+
+fn create_nowplaying_card(
+    request: &NowPlayingRequest,
+    artwork: Option<&DynamicImage>,
+    theme: &Theme,
+    text: &Localizer,
+) -> anyhow::Result<RgbaImage> {
+    let artwork_palette = artwork.map(|artwork| palette::extract(&artwork.to_rgba8()));
+    let mut background = match (artwork, &artwork_palette) {
+        (Some(artwork), Some(artwork_palette)) => {
+            let mut background = imaging::blurred_backdrop(artwork, CARD_WIDTH, CARD_HEIGHT, 0.55);
+            if let Some([r, g, b, _]) = theme::parse_color(Some(&artwork_palette.dominant))? {
+                imaging::tint(&mut background, [r, g, b], TINT_AMOUNT);
+            }
+            background
+        }
+        _ => {
+            let base = image::load_from_memory(&theme.background)?;
+            imaging::blurred_backdrop(&base, CARD_WIDTH, CARD_HEIGHT, 0.8)
+        }
+    };
+    // Pick the text color from the finished background so it always stays readable.
+    let background_palette = palette::extract(&background);
+    let text_color = theme::parse_color(Some(&background_palette.text))?
+        .map(rgba)
+        .unwrap_or(theme.text_color());
+    let progress_color = artwork_palette
+        .and_then(|artwork_palette| artwork_palette.vibrant)
+        .and_then(|vibrant| theme::parse_color(Some(&vibrant)).ok().flatten())
+        .unwrap_or(theme.colors.progress);
+
+    let info_x = match artwork {
+        Some(_) => PADDING + ARTWORK_SIZE + ARTWORK_GAP,
+        None => PADDING,
+    };
+    let info_width = CARD_WIDTH - info_x - PADDING;
+    let artwork_right = match artwork {
+        Some(_) => info_x,
+        None => 0,
+    };
+    let time_top = PADDING + ARTWORK_SIZE - TIME_FONT_SIZE as u32 - 4;
+    let progress_y = time_top - 12 - PROGRESS_HEIGHT;
+
+    let mut writer = OGImageWriter::from_data(
+        style::WindowStyle {
+            align_items: style::AlignItems::Start,
+            justify_content: style::JustifyContent::Start,
+            width: CARD_WIDTH,
+            height: CARD_HEIGHT,
+            flex_direction: style::FlexDirection::Row,
+            ..style::WindowStyle::default()
+        },
+        &encoding::encode_png(&background)?,
+        ImageInputFormat::Png,
+    )?;
+
+    if let Some(artwork) = artwork {
+        let artwork = imaging::cover_fit(artwork, ARTWORK_SIZE, ARTWORK_SIZE);
+        writer.set_img_with_data(
+            &encoding::encode_png(&artwork)?,
+            ARTWORK_SIZE,
+            ARTWORK_SIZE,
+            ImageInputFormat::Png,
+            style::Style {
+                margin: style::Margin(PADDING as i32, ARTWORK_GAP as i32, 0, PADDING as i32),
+                border_radius: style::BorderRadius(16, 16, 16, 16),
+                ..style::Style::default()
+            },
+        )?;
+    }
+
+    // The light font has no CJK glyphs, use the bold one for those languages.
+    let font_light = if text.lang.cjk {
+        &theme.font_bold
+    } else {
+        &theme.font_light
+    };
+
+    let mut info = OGImageWriter::new(style::WindowStyle {
+        width: info_width,
+        height: progress_y - PADDING - 16,
+        align_items: style::AlignItems::Start,
+        justify_content: style::JustifyContent::Start,
+        flex_direction: style::FlexDirection::Column,
+        ..style::WindowStyle::default()
+    })?;
+    info.set_text(
+        &request.title,
+        style::Style {
+            font_size: 52.,
+            color: text_color,
+            word_break: style::WordBreak::BreakAll,
+            max_width: Some(info_width),
+            max_height: Some(140),
+            text_overflow: style::TextOverflow::Ellipsis,
+            ..style::Style::default()
+        },
+        Some(theme.font_bold.to_vec()),
+    )?;
+    if let Some(artist) = request
+        .artist
+        .as_deref()
+        .filter(|artist| !artist.is_empty())
+    {
+        info.set_text(
+            artist,
+            style::Style {
+                margin: style::Margin(12, 0, 0, 0),
+                font_size: 34.,
+                color: text_color,
+                max_width: Some(info_width),
+                max_height: Some(48),
+                text_overflow: style::TextOverflow::Ellipsis,
+                ..style::Style::default()
+            },
+            Some(font_light.to_vec()),
+        )?;
+    }
+    if let Some(requester) = request
+        .requester
+        .as_deref()
+        .filter(|requester| !requester.is_empty())
+    {
+        let mut args = FluentArgs::new();
+        args.set("name", requester);
+        info.set_text(
+            &text.message("nowplaying-requester", Some(&args)),
+            style::Style {
+                margin: style::Margin(24, 0, 0, 0),
+                font_size: 24.,
+                color: text_color,
+                max_width: Some(info_width),
+                max_height: Some(36),
+                text_overflow: style::TextOverflow::Ellipsis,
+                ..style::Style::default()
+            },
+            Some(font_light.to_vec()),
+        )?;
+    }
+    writer.set_container(
+        &mut info,
+        style::Style {
+            // Next to the artwork, its own margin already spaces the info out.
+            margin: style::Margin(PADDING as i32, 0, 0, (info_x - artwork_right) as i32),
+            ..style::Style::default()
+        },
+    )?;
+
+    let progress = request.duration.map(|duration| {
+        let position = request.position.unwrap_or_default().min(duration);
+        (position, duration)
+    });
+    if let Some((position, duration)) = progress {
+        let time_style = |left: Option<i32>, right: Option<i32>| style::Style {
+            font_size: TIME_FONT_SIZE,
+            color: text_color,
+            position: style::Position::Absolute,
+            top: Some(time_top as i32),
+            left,
+            right,
+            ..style::Style::default()
+        };
+        writer.set_text(
+            &format_time(position),
+            time_style(Some(info_x as i32), None),
+            Some(theme.font_bold.to_vec()),
+        )?;
+        writer.set_text(
+            &format_time(duration),
+            time_style(None, Some(PADDING as i32)),
+            Some(theme.font_bold.to_vec()),
+        )?;
+    }
+
+    writer.paint()?;
+    let (width, height) = (writer.width(), writer.height());
+    // og_image_writer uses an older version of `image`, so move over the raw pixels.
+    background = RgbaImage::from_raw(width, height, writer.into_vec()?)
+        .ok_or_else(|| anyhow::anyhow!("Painted image does not match its dimensions"))?;
+
+    if let Some((position, duration)) = progress {
+        imaging::draw_progress_bar(
+            &mut background,
+            info_x,
+            progress_y,
+            info_width,
+            PROGRESS_HEIGHT,
+            (position / duration) as f32,
+            progress_color,
+            theme.colors.progress_track,
+        );
+    }
+    Ok(background)
+}
+
+pub async fn handle_nowplaying_card(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    request: Query<NowPlayingRequest>,
+) -> Result<Response, AppError> {
+    if !signing::is_authorized(
+        state.signing_secret.as_deref(),
+        &request.0,
+        request.sig.as_deref(),
+    ) {
+        return Err(AppError::Forbidden(
+            "Invalid or missing signature".to_string(),
+        ));
+    }
+
+    let link = MusicLink::parse(&request.url).map_err(AppError::BadRequest)?;
+    if let Some(duration) = request.duration {
+        if !(duration > 0. && duration.is_finite()) {
+            return Err(AppError::BadRequest(format!(
+                "Duration must be a positive number of seconds, got {}",
+                duration
+            )));
+        }
+    }
+    if let Some(position) = request.position {
+        if !(position >= 0. && position.is_finite()) {
+            return Err(AppError::BadRequest(format!(
+                "Position must not be negative, got {}",
+                position
+            )));
+        }
+    }
+    let negotiated =
+        encoding::negotiate(request.format.as_deref(), &headers).map_err(AppError::BadRequest)?;
+    let negotiated_lang = state
+        .i18n
+        .negotiate(request.lang.as_deref(), &headers)
+        .map_err(AppError::BadRequest)?;
+    let theme = state.themes.get(request.theme.as_deref()).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "Unknown theme: `{}`",
+            request.theme.as_deref().unwrap_or_default()
+        ))
+    })?;
+
+    let card = Card {
+        label: "nowplaying",
+        path: "/music/nowplaying",
+        description: "now playing card",
+        key: RenderCache::key(&[
+            "nowplaying",
+            &signing::canonical_query(&request.0),
+            negotiated.format.extension(),
+            negotiated_lang.lang.tag,
+            &theme.name,
+            &theme.fingerprint,
+        ]),
+        query: serde_qs::to_string(&request.0).unwrap_or_default(),
+        output: RenderOutput::Image {
+            negotiated,
+            quality: request.quality,
+            name: "NowPlaying",
+        },
+        lang_from_header: negotiated_lang.used_header,
+    };
+
+    let artwork_state = state.clone();
+    let prepare = async move {
+        // Missing artwork should not break the card, render without it instead.
+        let artwork = match music_thumb::fetch_artwork(&artwork_state, &link).await {
+            Ok(artwork) => Some(artwork),
+            Err(err) => {
+                warn!(
+                    "Failed to fetch artwork of {}: {}",
+                    request.url,
+                    err.message()
+                );
+                None
+            }
+        };
+        // Keep retrying the artwork instead of caching the card drawn without it.
+        let cacheable = artwork.is_some();
+        let catalog = artwork_state.i18n.clone();
+        let draw = move |uuid: &str| {
+            info!(
+                "Generating now playing card {} with data: {:?}",
+                uuid, request
+            );
+            let text = catalog.localizer(negotiated_lang.lang);
+            create_nowplaying_card(&request, artwork.as_ref(), &theme, &text)
+        };
+        Prepared { draw, cacheable }
+    };
+    cache::serve_render(state, headers, card, prepare).await
+}
@@ -0,0 +1,238 @@
+/// Resolve any supported music link to its thumbnail, without knowing the provider.
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::Response,
+};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    encoding::OutputQuery,
+    prelude::AppError,
+    routes::music_thumb::{
+        self, AppleMusicRequest, BandcampRequest, SoundcloudRequest, SpotifyRequest,
+        TransformQuery, YTMRequest,
+    },
+    AppState,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct ResolveRequest {
+    url: String,
+}
+
+/// A music link, split into what its provider route needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MusicLink {
+    Bandcamp { url: String },
+    SoundCloud { artist: String, title: String },
+    Spotify { kind: String, id: String },
+    AppleMusic { storefront: String, id: String },
+    YouTube { id: String },
+}
+
+impl MusicLink {
+    /// Detect the provider of a link.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let parsed =
+            reqwest::Url::parse(url.trim()).map_err(|_| format!("Invalid URL: `{}`", url))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(format!("Unsupported URL scheme: `{}`", parsed.scheme()));
+        }
+        let host = parsed.host_str().unwrap_or_default().to_ascii_lowercase();
+        let host = host.strip_prefix("www.").unwrap_or(&host);
+        let segments: Vec<&str> = parsed
+            .path_segments()
+            .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+            .unwrap_or_default();
+        let query = |key: &str| {
+            parsed
+                .query_pairs()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.to_string())
+        };
+        let unsupported = || format!("Unsupported music link: `{}`", url);
+
+        match host {
+            "youtu.be" => segments
+                .first()
+                .map(|id| MusicLink::YouTube { id: id.to_string() })
+                .ok_or_else(unsupported),
+            "youtube.com" | "m.youtube.com" | "music.youtube.com" => match segments.as_slice() {
+                ["watch"] => query("v").map(|id| MusicLink::YouTube { id }),
+                ["shorts" | "embed" | "live", id] => {
+                    Some(MusicLink::YouTube { id: id.to_string() })
+                }
+                _ => None,
+            }
+            .ok_or_else(unsupported),
+            "soundcloud.com" | "m.soundcloud.com" => match segments.as_slice() {
+                [artist, title] => Ok(MusicLink::SoundCloud {
+                    artist: artist.to_string(),
+                    title: title.to_string(),
+                }),
+                _ => Err(unsupported()),
+            },
+            "open.spotify.com" => {
+                // Localized links look like `/intl-ja/track/<id>`.
+                let segments = match segments.as_slice() {
+                    [locale, rest @ ..] if locale.starts_with("intl-") => rest,
+                    all => all,
+                };
+                match segments {
+                    [kind, id] => Ok(MusicLink::Spotify {
+                        kind: kind.to_string(),
+                        id: id.to_string(),
+                    }),
+                    _ => Err(unsupported()),
+                }
+            }
+            // `/<storefront>/album/<name>/<id>`, tracks carry their own id in `?i=`.
+            "music.apple.com" => match segments.as_slice() {
+                [storefront, "album" | "song" | "playlist", .., id] => Ok(MusicLink::AppleMusic {
+                    storefront: storefront.to_string(),
+                    id: query("i").unwrap_or(id.to_string()),
+                }),
+                _ => Err(unsupported()),
+            },
+            host if host.ends_with(".bandcamp.com") => match segments.as_slice() {
+                ["track" | "album", _] => Ok(MusicLink::Bandcamp {
+                    url: url.trim().to_string(),
+                }),
+                _ => Err(unsupported()),
+            },
+            _ => Err(format!("Unsupported music host: `{}`", host)),
+        }
+    }
+}
+
+pub async fn handle_music_resolve(
+    request: Query<ResolveRequest>,
+    transform: Query<TransformQuery>,
+    output: Query<OutputQuery>,
+    state: State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let link = MusicLink::parse(&request.url).map_err(AppError::BadRequest)?;
+    info!("Resolved music link {} to {:?}", request.url, link);
+
+    match link {
+        MusicLink::Bandcamp { url } => {
+            music_thumb::handle_bandcamp_thumb(
+                Query(BandcampRequest { url }),
+                transform,
+                output,
+                state,
+                og_headers,
+            )
+            .await
+        }
+        MusicLink::SoundCloud { artist, title } => {
+            music_thumb::handle_soundcloud_thumb(
+                Path(SoundcloudRequest { artist, title }),
+                transform,
+                output,
+                state,
+                og_headers,
+            )
+            .await
+        }
+        MusicLink::Spotify { kind, id } => {
+            music_thumb::handle_spotify_thumb(
+                Path(SpotifyRequest { kind, id }),
+                transform,
+                output,
+                state,
+                og_headers,
+            )
+            .await
+        }
+        MusicLink::AppleMusic { storefront, id } => {
+            music_thumb::handle_apple_music_thumb(
+                Path(AppleMusicRequest { storefront, id }),
+                transform,
+                output,
+                state,
+                og_headers,
+            )
+            .await
+        }
+        MusicLink::YouTube { id } => {
+            music_thumb::handle_youtube_music_thumb(
+                Path(YTMRequest { id }),
+                transform,
+                output,
+                state,
+                og_headers,
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn youtube(id: &str) -> MusicLink {
+        MusicLink::YouTube { id: id.to_string() }
+    }
+
+    #[test]
+    fn parses_youtube_links() {
+        for url in [
+            "https://music.youtube.com/watch?v=abc123&list=x",
+            "https://www.youtube.com/watch?v=abc123",
+            "https://youtu.be/abc123",
+            "https://youtube.com/shorts/abc123",
+        ] {
+            assert_eq!(MusicLink::parse(url), Ok(youtube("abc123")), "{}", url);
+        }
+    }
+
+    #[test]
+    fn parses_provider_links() {
+        assert_eq!(
+            MusicLink::parse("https://soundcloud.com/artist/title"),
+            Ok(MusicLink::SoundCloud {
+                artist: "artist".to_string(),
+                title: "title".to_string(),
+            })
+        );
+        assert_eq!(
+            MusicLink::parse("https://open.spotify.com/intl-ja/track/4uLU6hMCjMI75M1A2tKUQC"),
+            Ok(MusicLink::Spotify {
+                kind: "track".to_string(),
+                id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            })
+        );
+        assert_eq!(
+            MusicLink::parse("https://music.apple.com/jp/album/name/1440857781?i=1440857795"),
+            Ok(MusicLink::AppleMusic {
+                storefront: "jp".to_string(),
+                id: "1440857795".to_string(),
+            })
+        );
+        assert_eq!(
+            MusicLink::parse(" https://artist.bandcamp.com/track/song "),
+            Ok(MusicLink::Bandcamp {
+                url: "https://artist.bandcamp.com/track/song".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_links() {
+        for url in [
+            "not a url",
+            "ftp://youtu.be/abc123",
+            "https://youtube.com/feed",
+            "https://soundcloud.com/artist",
+            "https://artist.bandcamp.com/",
+            "https://example.com/track/1",
+        ] {
+            assert!(MusicLink::parse(url).is_err(), "{}", url);
+        }
+    }
+}
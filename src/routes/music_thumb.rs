@@ -1,238 +1,1204 @@
-use std::io::Cursor;
-
 /// Music Thumbnail fetcher for the music player in naoTimes
+use std::future::Future;
+
 use axum::{
     extract::{Path, Query, State},
     http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Redirect, Response},
 };
-use image::ImageBuffer;
+use image::{DynamicImage, RgbaImage};
 use scraper::Selector;
 use serde::Deserialize;
+use tokio::task;
 use tracing::info;
 use urlencoding::{decode, encode};
 
-use crate::{report_plausible_event, AppState, PlausibleEvent, PlausibleMetadata};
-
-static USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/74.0.3729.115 Safari/537.36";
+use crate::{
+    analytics::report_event,
+    cache::RenderCache,
+    encoding::{self, Negotiated, OutputQuery},
+    env::get_env,
+    fetch,
+    imaging::{self, Fit},
+    palette,
+    prelude::AppError,
+    routes::music_resolve::MusicLink,
+    upstream::Upstream,
+    AnalyticsEvent, AppState, VisitorMetadata,
+};
 
 #[derive(Deserialize, Debug)]
 pub struct BandcampRequest {
-    url: String,
+    pub(crate) url: String,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct SoundcloudRequest {
-    artist: String,
-    title: String,
+    pub(crate) artist: String,
+    pub(crate) title: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SpotifyRequest {
+    pub(crate) kind: String,
+    pub(crate) id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AppleMusicRequest {
+    pub(crate) storefront: String,
+    pub(crate) id: String,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct YTMRequest {
-    id: String,
+    pub(crate) id: String,
+}
+
+/// Spotify links we can resolve artwork for.
+static SPOTIFY_KINDS: [&str; 4] = ["track", "album", "playlist", "episode"];
+
+/// YouTube thumbnails from best to worst, not every video has all of them.
+static YTM_THUMBNAILS: [&str; 4] = ["maxresdefault", "sddefault", "hqdefault", "mqdefault"];
+/// Video IDs are 11 characters today, leave room without accepting arbitrary input.
+const MAX_YTM_ID_LEN: usize = 64;
+
+/// Hosts `/music/bandcamp?url=` may fetch from when `BANDCAMP_ALLOWED_HOSTS` is unset.
+pub static DEFAULT_BANDCAMP_HOSTS: [&str; 1] = ["bandcamp.com"];
+/// CDN serving the images linked from Bandcamp pages.
+pub static BANDCAMP_IMAGE_HOST: &str = "bcbits.com";
+
+/// Artwork size of Apple Music links when `size` is not given.
+const APPLE_DEFAULT_SIZE: u32 = 1000;
+
+/// Resolved image URLs of Bandcamp, SoundCloud, Spotify and Apple Music pages.
+const URL_NAMESPACE: &str = "thumb-urls";
+/// Encoded YouTube Music thumbnails.
+const THUMB_NAMESPACE: &str = "thumbs";
+/// Palettes of thumbnails as JSON, keyed like the thumbnail they belong to.
+const PALETTE_NAMESPACE: &str = "palettes";
+/// Size of Apple Music artwork fetched for a palette, more pixels would not change it.
+const APPLE_PALETTE_SIZE: u32 = 300;
+
+pub(crate) async fn disk_get(state: &AppState, namespace: &str, key: &str) -> Option<Vec<u8>> {
+    state.disk_cache.as_ref()?.get(namespace, key).await
+}
+
+pub(crate) async fn disk_put(state: &AppState, namespace: &str, key: &str, data: &[u8]) {
+    if let Some(disk) = &state.disk_cache {
+        disk.put(namespace, key, data).await;
+    }
+}
+
+/// Find the first element matching `selector` and take its `attribute`.
+///
+/// The parsed document is not `Send`, so it must not live across an await point.
+pub(crate) fn find_attribute(html: &str, selector: &str, attribute: &str) -> Option<String> {
+    let parsed_html = scraper::Html::parse_document(html);
+    let selector = Selector::parse(selector).unwrap();
+    parsed_html
+        .select(&selector)
+        .next()
+        .and_then(|element| element.attr(attribute))
+        .map(|value| value.to_string())
+}
+
+async fn cached_url(state: &AppState, key: &str) -> Option<String> {
+    String::from_utf8(disk_get(state, URL_NAMESPACE, key).await?).ok()
+}
+
+/// How resolved Bandcamp, SoundCloud, Spotify and Apple Music thumbnails are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThumbMode {
+    /// Redirect to the image on the provider CDN.
+    Redirect,
+    /// Download, transform and serve the image ourselves.
+    Proxy,
+    /// Serve the color palette of the image as JSON.
+    Palette,
+}
+
+impl ThumbMode {
+    /// Parse `mode`, defaulting to a proxy when the image has to be transformed.
+    fn from_query(mode: Option<&str>, transformed: bool) -> Result<Self, String> {
+        match mode.map(|mode| mode.to_ascii_lowercase()).as_deref() {
+            None if transformed => Ok(ThumbMode::Proxy),
+            None => Ok(ThumbMode::Redirect),
+            Some("redirect") if transformed => {
+                Err("`size` and `fit` need `mode=proxy`, redirects can not be resized".to_string())
+            }
+            Some("redirect") => Ok(ThumbMode::Redirect),
+            Some("proxy") => Ok(ThumbMode::Proxy),
+            Some("palette") => Ok(ThumbMode::Palette),
+            Some(other) => Err(format!(
+                "Unknown mode: `{}`, expected `redirect`, `proxy` or `palette`",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TransformQuery {
+    /// `redirect`, `proxy` or `palette`, defaults to `proxy` when `size` or `fit` is given.
+    mode: Option<String>,
+    /// Size of the image in pixels, the longest side for `fit=contain`.
+    size: Option<u32>,
+    /// `cover`, `contain` or `blur-pad`, see [`Fit`].
+    fit: Option<String>,
+    /// Alias of `fit=cover`.
+    square: Option<bool>,
+}
+
+/// Bounds for the `size` parameter.
+const MIN_THUMB_SIZE: u32 = 16;
+const MAX_THUMB_SIZE: u32 = 2048;
+
+impl TransformQuery {
+    /// Query of the `/palette` routes.
+    fn palette() -> Self {
+        TransformQuery {
+            mode: Some("palette".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn is_palette(&self) -> bool {
+        self.mode
+            .as_deref()
+            .is_some_and(|mode| mode.eq_ignore_ascii_case("palette"))
+    }
+
+    /// Validate `size` and `fit`.
+    fn resolve(&self) -> Result<(Option<u32>, Option<Fit>), String> {
+        if let Some(size) = self.size {
+            if !(MIN_THUMB_SIZE..=MAX_THUMB_SIZE).contains(&size) {
+                return Err(format!(
+                    "Size must be between {} and {} pixels, got {}",
+                    MIN_THUMB_SIZE, MAX_THUMB_SIZE, size
+                ));
+            }
+        }
+        let fit = match self.fit.as_deref() {
+            Some(name) => Some(Fit::from_name(name).ok_or_else(|| {
+                format!(
+                    "Unknown fit: `{}`, expected `cover`, `contain` or `blur-pad`",
+                    name
+                )
+            })?),
+            None if self.square == Some(true) => Some(Fit::Cover),
+            None => None,
+        };
+        Ok((self.size, fit))
+    }
+}
+
+/// Validated options of a proxied thumbnail.
+struct ProxyOptions {
+    negotiated: Negotiated,
+    size: Option<u32>,
+    fit: Fit,
+    quality: Option<u8>,
+}
+
+/// What a resolved thumbnail is served as.
+enum ThumbOutput {
+    Redirect,
+    Proxy(ProxyOptions),
+    Palette,
+}
+
+/// Validate the query up front.
+///
+/// `sized_upstream` is set when the provider already serves the image at `size`.
+fn thumb_output(
+    transform: &TransformQuery,
+    output: &OutputQuery,
+    headers: &HeaderMap,
+    sized_upstream: bool,
+) -> Result<ThumbOutput, String> {
+    let (size, fit) = transform.resolve()?;
+    let transformed = fit.is_some() || (size.is_some() && !sized_upstream);
+    match ThumbMode::from_query(transform.mode.as_deref(), transformed)? {
+        ThumbMode::Redirect => Ok(ThumbOutput::Redirect),
+        ThumbMode::Palette => Ok(ThumbOutput::Palette),
+        ThumbMode::Proxy => Ok(ThumbOutput::Proxy(ProxyOptions {
+            negotiated: encoding::negotiate(output.format.as_deref(), headers)?,
+            size,
+            fit: fit.unwrap_or(Fit::Contain),
+            quality: output.quality,
+        })),
+    }
+}
+
+fn palette_key(cache_key: &str) -> String {
+    RenderCache::key(&["palette", cache_key])
+}
+
+fn palette_response(json: Vec<u8>) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+    headers.insert(
+        header::CACHE_CONTROL,
+        "public, max-age=7200".parse().unwrap(),
+    );
+    (StatusCode::OK, headers, json).into_response()
+}
+
+/// Extract the palette of `image` and cache it under `palette_key`.
+async fn serve_new_palette(
+    state: &AppState,
+    palette_key: &str,
+    image: DynamicImage,
+) -> Result<Response, AppError> {
+    let palette = task::spawn_blocking(move || palette::extract(&image.into_rgba8()))
+        .await
+        .map_err(|err| AppError::internal(err, "Error extracting palette"))?;
+    let json = serde_json::to_vec(&palette).unwrap_or_default();
+    disk_put(state, PALETTE_NAMESPACE, palette_key, &json).await;
+    Ok(palette_response(json))
+}
+
+/// Serve the palette of a resolved thumbnail.
+async fn serve_palette(
+    state: &AppState,
+    upstream: &Upstream,
+    href: String,
+    cache_key: &str,
+) -> Result<Response, AppError> {
+    let palette_key = palette_key(cache_key);
+    if let Some(json) = disk_get(state, PALETTE_NAMESPACE, &palette_key).await {
+        return Ok(palette_response(json));
+    }
+    let image = fetch::fetch_image(upstream, &href)
+        .await
+        .map_err(|err| AppError::upstream_any(&err, "Failed to fetch image"))?;
+    serve_new_palette(state, &palette_key, image).await
+}
+
+/// Serve a resolved thumbnail as a redirect, proxied through us or as its palette.
+///
+/// `upstream` downloads the image, restricted for hrefs taken from user supplied pages.
+async fn serve_thumb(
+    state: &AppState,
+    upstream: &Upstream,
+    href: String,
+    cache_key: &str,
+    options: ThumbOutput,
+) -> Result<Response, AppError> {
+    let ProxyOptions {
+        negotiated,
+        size,
+        fit,
+        quality,
+    } = match options {
+        ThumbOutput::Proxy(options) => options,
+        ThumbOutput::Redirect => return Ok(Redirect::to(&href).into_response()),
+        ThumbOutput::Palette => return serve_palette(state, upstream, href, cache_key).await,
+    };
+
+    let size_key = size.map(|size| size.to_string());
+    let quality_key = quality.map(|quality| quality.to_string());
+    let proxy_key = RenderCache::key(&[
+        "proxy",
+        cache_key,
+        size_key.as_deref().unwrap_or_default(),
+        fit.name(),
+        negotiated.format.extension(),
+        quality_key.as_deref().unwrap_or_default(),
+    ]);
+
+    let mut resp_headers = HeaderMap::new();
+    encoding::insert_image_headers(
+        &mut resp_headers,
+        &negotiated,
+        &format!("{}.thumb", &proxy_key[..16]),
+    )?;
+    resp_headers.insert(
+        header::CACHE_CONTROL,
+        "public, max-age=7200".parse().unwrap(),
+    );
+    if let Some(data) = disk_get(state, THUMB_NAMESPACE, &proxy_key).await {
+        return Ok((StatusCode::OK, resp_headers, data).into_response());
+    }
+
+    let image = fetch::fetch_image(upstream, &href)
+        .await
+        .map_err(|err| AppError::upstream_any(&err, "Failed to fetch image"))?;
+
+    let format = negotiated.format;
+    let data = task::spawn_blocking(move || {
+        let thumb = imaging::fit_image(&image, size, fit);
+        encoding::encode_image(&thumb, format, quality)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|res| res)
+    .map_err(|err| AppError::internal(err, "Error encoding thumbnail"))?;
+
+    disk_put(state, THUMB_NAMESPACE, &proxy_key, &data).await;
+    Ok((StatusCode::OK, resp_headers, data).into_response())
+}
+
+/// Resolve a thumbnail URL from the disk cache, or with `resolve` and cache it.
+async fn cached_or_resolve(
+    state: &AppState,
+    cache_key: &str,
+    resolve: impl Future<Output = Result<String, AppError>>,
+) -> Result<String, AppError> {
+    if let Some(href) = cached_url(state, cache_key).await {
+        return Ok(href);
+    }
+    let resolved = resolve.await;
+    if let Ok(href) = &resolved {
+        disk_put(state, URL_NAMESPACE, cache_key, href.as_bytes()).await;
+    }
+    resolved
+}
+
+pub(crate) fn fetch_failed(err: reqwest::Error) -> AppError {
+    AppError::upstream(&err, "Failed to fetch URL")
+}
+
+/// Reading an upstream body failed or it was over the size limit.
+pub(crate) fn body_failed(err: anyhow::Error) -> AppError {
+    AppError::upstream_any(&err, "Failed to fetch URL")
+}
+
+/// The upstream answered, but not with anything we can use.
+pub(crate) fn upstream_failed() -> AppError {
+    AppError::Upstream("Failed to fetch URL".to_string())
+}
+
+/// Scrape the image of a page from `selector`, `not_found` is the error of a missing page.
+async fn resolve_page_image(
+    upstream: &Upstream,
+    url: &str,
+    selector: &str,
+    attribute: &str,
+    not_found: String,
+) -> Result<String, AppError> {
+    let req = upstream.get(url).await.map_err(fetch_failed)?;
+    if req.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(AppError::NotFound(not_found));
+    }
+    if !req.status().is_success() {
+        return Err(upstream_failed());
+    }
+    let html = upstream.text(req).await.map_err(body_failed)?;
+    find_attribute(&html, selector, attribute)
+        .ok_or(AppError::NotFound("Failed to find image".to_string()))
 }
 
-fn reqwest_client() -> reqwest::Client {
-    reqwest::Client::builder()
-        .user_agent(USER_AGENT.to_string())
-        .build()
-        .unwrap()
+/// Refuse Bandcamp URLs the restricted `upstream` may not reach before fetching them.
+pub(crate) fn check_bandcamp_url(upstream: &Upstream, url: &str) -> Result<(), AppError> {
+    let parsed =
+        reqwest::Url::parse(url).map_err(|_| AppError::BadRequest("Invalid URL".to_string()))?;
+    upstream
+        .check(&parsed)
+        .map_err(|blocked| AppError::BadRequest(blocked.to_string()))
+}
+
+async fn resolve_bandcamp_artwork(upstream: &Upstream, url: &str) -> Result<String, AppError> {
+    check_bandcamp_url(upstream, url)?;
+    resolve_page_image(
+        upstream,
+        url,
+        r#"link[rel="image_src"]"#,
+        "href",
+        format!("Bandcamp not found: `{}`", url),
+    )
+    .await
+}
+
+async fn resolve_soundcloud_artwork(
+    upstream: &Upstream,
+    artist: &str,
+    title: &str,
+) -> Result<String, AppError> {
+    resolve_page_image(
+        upstream,
+        &format!("https://soundcloud.com/{}/{}", artist, title),
+        r#"meta[property="og:image"]"#,
+        "content",
+        format!("Soundcloud track not found: `/{}/{}`", artist, title),
+    )
+    .await
 }
 
 pub async fn handle_bandcamp_thumb(
     query: Query<BandcampRequest>,
+    transform: Query<TransformQuery>,
+    output: Query<OutputQuery>,
     State(state): State<AppState>,
     og_headers: HeaderMap,
-) -> Response {
+) -> Result<Response, AppError> {
     let decode_url = decode(&query.url)
-        .expect("Failed to decode URL")
+        .map_err(|_| AppError::BadRequest("Invalid URL".to_string()))?
         .to_string();
 
     info!("Processing bandcamp URL: {}", decode_url);
 
-    let req = reqwest_client()
-        .get(decode_url.clone())
-        .send()
-        .await
-        .unwrap();
+    let options =
+        thumb_output(&transform, &output, &og_headers, false).map_err(AppError::BadRequest)?;
 
-    let metadata: PlausibleMetadata = og_headers.into();
-    let event = PlausibleEvent::default()
+    let cache_key = RenderCache::key(&["bandcamp", &decode_url]);
+    let resolved = cached_or_resolve(
+        &state,
+        &cache_key,
+        resolve_bandcamp_artwork(&state.bandcamp_upstream, &decode_url),
+    )
+    .await;
+
+    let metadata: VisitorMetadata = og_headers.into();
+    let event = AnalyticsEvent::default()
         .with_url(format!("/music/bandcamp?url={}", encode(&decode_url)))
         .with_props(serde_json::json!({
-            "success": req.status().is_success().to_string(),
+            "success": resolved.is_ok().to_string(),
         }));
-    report_plausible_event(state, event, metadata).await;
+    report_event(state.clone(), event, metadata).await;
 
-    if !req.status().is_success() {
-        if req.status() == reqwest::StatusCode::NOT_FOUND {
-            return (
-                StatusCode::NOT_FOUND,
-                format!("Bandcamp not found: `{}`", decode_url),
-            )
-                .into_response();
-        }
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch URL").into_response();
-    }
-
-    let html = req.text().await.unwrap();
-    let parsed_html = scraper::Html::parse_document(&html);
-    let selector = Selector::parse(r#"link[rel="image_src"]"#).unwrap();
-    let meta_head = parsed_html.select(&selector).next();
-
-    match meta_head {
-        Some(meta) => {
-            let href = meta.attr("href").unwrap();
-            Redirect::to(href).into_response()
-        }
-        None => (StatusCode::NOT_FOUND, "Failed to find image").into_response(),
-    }
+    serve_thumb(
+        &state,
+        &state.bandcamp_image_upstream,
+        resolved?,
+        &cache_key,
+        options,
+    )
+    .await
 }
 
 pub async fn handle_soundcloud_thumb(
     request: Path<SoundcloudRequest>,
+    transform: Query<TransformQuery>,
+    output: Query<OutputQuery>,
     State(state): State<AppState>,
     og_headers: HeaderMap,
-) -> Response {
+) -> Result<Response, AppError> {
     info!("Processing soundcloud URL: {:?}", request);
 
-    let req = reqwest_client()
-        .get(format!(
-            "https://soundcloud.com/{}/{}",
-            request.artist, request.title
-        ))
-        .send()
-        .await
-        .unwrap();
+    let options =
+        thumb_output(&transform, &output, &og_headers, false).map_err(AppError::BadRequest)?;
+
+    let cache_key = RenderCache::key(&["soundcloud", &request.artist, &request.title]);
+    let resolved = cached_or_resolve(
+        &state,
+        &cache_key,
+        resolve_soundcloud_artwork(&state.upstream, &request.artist, &request.title),
+    )
+    .await;
 
-    let metadata: PlausibleMetadata = og_headers.into();
-    let event = PlausibleEvent::default()
+    let metadata: VisitorMetadata = og_headers.into();
+    let event = AnalyticsEvent::default()
         .with_url(format!(
             "/music/soundcloud/{}/{}",
             request.artist, request.title
         ))
         .with_props(serde_json::json!({
-            "success": req.status().is_success().to_string(),
+            "success": resolved.is_ok().to_string(),
         }));
-    report_plausible_event(state, event, metadata).await;
+    report_event(state.clone(), event, metadata).await;
 
-    if !req.status().is_success() {
-        if req.status() == reqwest::StatusCode::NOT_FOUND {
-            return (
-                StatusCode::NOT_FOUND,
-                format!(
-                    "Soundcloud track not found: `/{}/{}`",
-                    request.artist, request.title
-                ),
-            )
-                .into_response();
+    serve_thumb(&state, &state.upstream, resolved?, &cache_key, options).await
+}
+
+#[derive(Deserialize, Debug)]
+struct SpotifyOEmbed {
+    thumbnail_url: Option<String>,
+}
+
+/// Base URL of Spotify, overridable with `SPOTIFY_BASE_URL` to use a stand-in server.
+fn spotify_base_url() -> String {
+    get_env("SPOTIFY_BASE_URL")
+        .unwrap_or("https://open.spotify.com".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Resolve the artwork of a Spotify link through oEmbed, falling back to the `og:image` of the page.
+async fn resolve_spotify_artwork(
+    upstream: &Upstream,
+    kind: &str,
+    id: &str,
+) -> Result<String, AppError> {
+    let base_url = spotify_base_url();
+    let not_found = || AppError::NotFound(format!("Spotify {} not found: `{}`", kind, id));
+
+    let oembed_url = format!(
+        "{}/oembed?url={}",
+        base_url,
+        encode(&format!("https://open.spotify.com/{}/{}", kind, id))
+    );
+    let req = upstream.get(oembed_url).await.map_err(fetch_failed)?;
+    // oEmbed answers 400 for malformed IDs and 404 for unknown ones.
+    if matches!(
+        req.status(),
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::BAD_REQUEST
+    ) {
+        return Err(not_found());
+    }
+    if req.status().is_success() {
+        let body = upstream.text(req).await.map_err(body_failed)?;
+        if let Ok(SpotifyOEmbed {
+            thumbnail_url: Some(href),
+        }) = serde_json::from_str(&body)
+        {
+            return Ok(href);
         }
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch URL").into_response();
     }
 
-    let html = req.text().await.unwrap();
+    info!(
+        "Spotify oEmbed has no artwork, trying the page: {}/{}",
+        kind, id
+    );
+    let req = upstream
+        .get(format!("{}/{}/{}", base_url, kind, id))
+        .await
+        .map_err(fetch_failed)?;
+    if req.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(not_found());
+    }
+    if !req.status().is_success() {
+        return Err(upstream_failed());
+    }
+    let html = upstream.text(req).await.map_err(body_failed)?;
+    find_attribute(&html, r#"meta[property="og:image"]"#, "content")
+        .ok_or(AppError::NotFound("Failed to find image".to_string()))
+}
 
-    let parsed_html = scraper::Html::parse_document(&html);
-    let selector = Selector::parse(r#"meta[property="og:image"]"#).unwrap();
+pub async fn handle_spotify_thumb(
+    request: Path<SpotifyRequest>,
+    transform: Query<TransformQuery>,
+    output: Query<OutputQuery>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    info!("Processing spotify URL: {:?}", request);
 
-    let meta_head = parsed_html.select(&selector).next();
+    let kind = request.kind.to_ascii_lowercase();
+    if !SPOTIFY_KINDS.contains(&kind.as_str()) {
+        return Err(AppError::BadRequest(format!(
+            "Unknown Spotify link kind: `{}`, expected one of {}",
+            request.kind,
+            SPOTIFY_KINDS.join(", ")
+        )));
+    }
+    if request.id.is_empty() || !request.id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid Spotify ID: `{}`",
+            request.id
+        )));
+    }
+    let options =
+        thumb_output(&transform, &output, &og_headers, false).map_err(AppError::BadRequest)?;
 
-    match meta_head {
-        Some(meta) => {
-            let href = meta.attr("content").unwrap();
-            Redirect::to(href).into_response()
-        }
-        None => (StatusCode::NOT_FOUND, "Failed to find image").into_response(),
+    let cache_key = RenderCache::key(&["spotify", &kind, &request.id]);
+    let metadata: VisitorMetadata = og_headers.into();
+    let event =
+        AnalyticsEvent::default().with_url(format!("/music/spotify/{}/{}", kind, request.id));
+
+    let resolved = cached_or_resolve(
+        &state,
+        &cache_key,
+        resolve_spotify_artwork(&state.upstream, &kind, &request.id),
+    )
+    .await;
+
+    let event = event.with_props(serde_json::json!({
+        "success": resolved.is_ok().to_string(),
+    }));
+    report_event(state.clone(), event, metadata).await;
+
+    serve_thumb(&state, &state.upstream, resolved?, &cache_key, options).await
+}
+
+#[derive(Deserialize, Debug)]
+struct ITunesLookup {
+    results: Vec<ITunesResult>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ITunesResult {
+    artwork_url100: Option<String>,
+}
+
+/// Base URL of the iTunes API, overridable with `ITUNES_BASE_URL` to use a stand-in server.
+fn itunes_base_url() -> String {
+    get_env("ITUNES_BASE_URL")
+        .unwrap_or("https://itunes.apple.com".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Swap the `100x100bb.jpg` part of an artwork URL for the requested size.
+fn sized_artwork_url(artwork_url: &str, size: u32) -> String {
+    match artwork_url.rsplit_once('/') {
+        Some((base, _)) => format!("{}/{}x{}bb.jpg", base, size, size),
+        None => artwork_url.to_string(),
+    }
+}
+
+async fn resolve_apple_artwork(
+    upstream: &Upstream,
+    storefront: &str,
+    id: &str,
+) -> Result<String, AppError> {
+    let req = upstream
+        .get(format!(
+            "{}/lookup?id={}&country={}",
+            itunes_base_url(),
+            id,
+            storefront
+        ))
+        .await
+        .map_err(fetch_failed)?;
+    if !req.status().is_success() {
+        return Err(upstream_failed());
     }
+    let body = upstream.text(req).await.map_err(body_failed)?;
+    let lookup: ITunesLookup = serde_json::from_str(&body).map_err(|_| upstream_failed())?;
+
+    lookup
+        .results
+        .into_iter()
+        .find_map(|result| result.artwork_url100)
+        .ok_or(AppError::NotFound(format!(
+            "Apple Music item not found: `/{}/{}`",
+            storefront, id
+        )))
 }
 
-fn create_ytm_thumb_square(bytes_data: Vec<u8>) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+pub async fn handle_apple_music_thumb(
+    request: Path<AppleMusicRequest>,
+    transform: Query<TransformQuery>,
+    output: Query<OutputQuery>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    info!("Processing Apple Music URL: {:?}", request);
+
+    let storefront = request.storefront.to_ascii_lowercase();
+    if storefront.len() != 2 || !storefront.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid storefront: `{}`",
+            request.storefront
+        )));
+    }
+    if request.id.is_empty() || !request.id.chars().all(|c| c.is_ascii_digit()) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid Apple Music ID: `{}`",
+            request.id
+        )));
+    }
+    let options =
+        thumb_output(&transform, &output, &og_headers, true).map_err(AppError::BadRequest)?;
+
+    let cache_key = RenderCache::key(&["apple", &storefront, &request.id]);
+    let metadata: VisitorMetadata = og_headers.into();
+    let event =
+        AnalyticsEvent::default().with_url(format!("/music/apple/{}/{}", storefront, request.id));
+
+    let resolved = cached_or_resolve(
+        &state,
+        &cache_key,
+        resolve_apple_artwork(&state.upstream, &storefront, &request.id),
+    )
+    .await;
+
+    let event = event.with_props(serde_json::json!({
+        "success": resolved.is_ok().to_string(),
+    }));
+    report_event(state.clone(), event, metadata).await;
+
+    // Apple renders artwork at any size, so ask for the one we want directly.
+    let size = match options {
+        ThumbOutput::Palette => APPLE_PALETTE_SIZE,
+        _ => transform
+            .size
+            .unwrap_or(APPLE_DEFAULT_SIZE)
+            .clamp(MIN_THUMB_SIZE, MAX_THUMB_SIZE),
+    };
+    let href = sized_artwork_url(&resolved?, size);
+    serve_thumb(&state, &state.upstream, href, &cache_key, options).await
+}
+
+/// Base URL of YouTube thumbnails, overridable with `YTIMG_BASE_URL` to use a stand-in server.
+fn ytimg_base_url() -> String {
+    get_env("YTIMG_BASE_URL")
+        .unwrap_or("https://i.ytimg.com".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn create_ytm_thumb(bytes_data: &[u8], size: Option<u32>, fit: Fit) -> anyhow::Result<RgbaImage> {
     info!(
-        "Creating YouTube Music Thumbnail Square: {} bytes",
+        "Creating YouTube Music Thumbnail ({}): {} bytes",
+        fit.name(),
         bytes_data.len()
     );
 
-    // Our original image is 1280x720, let's load it into an image buffer
-    let mut image = image::load_from_memory(&bytes_data).unwrap();
-    // Crop the image to 720x720, with gravity center
-    let cropped_image = image::imageops::crop(&mut image, 280, 0, 720, 720).to_image();
-    cropped_image
+    let image = image::load_from_memory(bytes_data)?;
+    Ok(imaging::fit_image(&imaging::trim_bars(&image), size, fit))
+}
+
+/// Refuse anything that does not look like a YouTube video ID before it reaches a URL or header.
+pub(crate) fn check_ytm_id(id: &str) -> Result<(), AppError> {
+    let valid = (1..=MAX_YTM_ID_LEN).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(AppError::BadRequest(format!(
+            "Invalid YouTube ID: `{}`",
+            id.escape_debug()
+        )));
+    }
+    Ok(())
+}
+
+/// Download the best available thumbnail.
+async fn fetch_ytm_thumbnail(upstream: &Upstream, id: &str) -> Result<Vec<u8>, AppError> {
+    let base_url = ytimg_base_url();
+    for name in YTM_THUMBNAILS {
+        let req = upstream
+            .get(format!("{}/vi/{}/{}.jpg", base_url, id, name))
+            .await
+            .map_err(fetch_failed)?;
+        if req.status() == reqwest::StatusCode::NOT_FOUND {
+            continue;
+        }
+        if !req.status().is_success() {
+            return Err(upstream_failed());
+        }
+        info!("Using YouTube thumbnail {} for {}", name, id);
+        return upstream.bytes(req).await.map_err(body_failed);
+    }
+    Err(AppError::NotFound(format!(
+        "YouTube Music track not found: `{}`",
+        id
+    )))
+}
+
+/// Decode a downloaded YouTube thumbnail, without its letterbox bars.
+fn decode_ytm_thumbnail(data: &[u8]) -> Result<DynamicImage, AppError> {
+    let image = image::load_from_memory(data).map_err(|err| {
+        tracing::error!("Failed to decode YouTube thumbnail: {}", err);
+        AppError::Upstream("Failed to fetch image".to_string())
+    })?;
+    Ok(imaging::trim_bars(&image))
+}
+
+/// Report a YouTube Music request, disk cache hits included.
+async fn report_ytm_event(state: &AppState, id: &str, success: bool, og_headers: HeaderMap) {
+    let metadata: VisitorMetadata = og_headers.into();
+    let event = AnalyticsEvent::default()
+        .with_url(format!("/music/ytm/{}", id))
+        .with_props(serde_json::json!({
+            "success": success.to_string(),
+        }));
+    report_event(state.clone(), event, metadata).await;
+}
+
+/// Palette of a YouTube Music thumbnail, taken after the letterbox bars are trimmed.
+async fn serve_ytm_palette(
+    state: AppState,
+    id: &str,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    check_ytm_id(id)?;
+    let palette_key = palette_key(&RenderCache::key(&["ytm", id]));
+    if let Some(json) = disk_get(&state, PALETTE_NAMESPACE, &palette_key).await {
+        report_ytm_event(&state, id, true, og_headers).await;
+        return Ok(palette_response(json));
+    }
+
+    let fetched = fetch_ytm_thumbnail(&state.upstream, id).await;
+    report_ytm_event(&state, id, fetched.is_ok(), og_headers).await;
+
+    let image = decode_ytm_thumbnail(&fetched?)?;
+    serve_new_palette(&state, &palette_key, image).await
 }
 
 pub async fn handle_youtube_music_thumb(
     request: Path<YTMRequest>,
+    transform: Query<TransformQuery>,
+    output: Query<OutputQuery>,
     State(state): State<AppState>,
     og_headers: HeaderMap,
-) -> impl IntoResponse {
+) -> Result<Response, AppError> {
     info!("Processing YouTube Music URL: {:?}", request);
+    check_ytm_id(&request.id)?;
 
-    let req = reqwest_client()
-        .get(format!(
-            "https://i.ytimg.com/vi/{}/maxresdefault.jpg",
-            request.id
-        ))
-        .send()
+    if transform.is_palette() {
+        return serve_ytm_palette(state, &request.id, og_headers).await;
+    }
+
+    let negotiated =
+        encoding::negotiate(output.format.as_deref(), &og_headers).map_err(AppError::BadRequest)?;
+    // Thumbnails are always served by us, so `mode` only matters for palettes.
+    let (size, fit) = transform.resolve().map_err(AppError::BadRequest)?;
+    let fit = fit.unwrap_or(Fit::Cover);
+
+    let quality = output.quality.map(|quality| quality.to_string());
+    let size_key = size.map(|size| size.to_string());
+    let cache_key = RenderCache::key(&[
+        "ytm",
+        &request.id,
+        size_key.as_deref().unwrap_or_default(),
+        fit.name(),
+        negotiated.format.extension(),
+        quality.as_deref().unwrap_or_default(),
+    ]);
+    let mut image_headers = HeaderMap::new();
+    encoding::insert_image_headers(
+        &mut image_headers,
+        &negotiated,
+        &format!("{}.thumb", request.id),
+    )?;
+    // Add cache control similar to what Youtube does.
+    image_headers.insert(
+        header::CACHE_CONTROL,
+        "public, max-age=7200".parse().unwrap(),
+    );
+    if let Some(data) = disk_get(&state, THUMB_NAMESPACE, &cache_key).await {
+        report_ytm_event(&state, &request.id, true, og_headers).await;
+        return Ok((StatusCode::OK, image_headers, data).into_response());
+    }
+
+    let fetched = fetch_ytm_thumbnail(&state.upstream, &request.id).await;
+    report_ytm_event(&state, &request.id, fetched.is_ok(), og_headers).await;
+
+    let image_data = fetched?;
+    let format = negotiated.format;
+    let quality = output.quality;
+    let data = task::spawn_blocking(move || {
+        create_ytm_thumb(&image_data, size, fit)
+            .and_then(|cropped_image| encoding::encode_image(&cropped_image, format, quality))
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|res| res)
+    .map_err(|err| AppError::internal(err, "Error writing cropped image to buffer"))?;
+
+    disk_put(&state, THUMB_NAMESPACE, &cache_key, &data).await;
+    Ok((StatusCode::OK, image_headers, data).into_response())
+}
+
+/// Download the artwork of any supported link, sharing the resolved URL cache of the thumbnail routes.
+pub(crate) async fn fetch_artwork(
+    state: &AppState,
+    link: &MusicLink,
+) -> Result<DynamicImage, AppError> {
+    // Bandcamp artwork comes from a user supplied page, fetch it as restricted as the page.
+    let upstream = match link {
+        MusicLink::Bandcamp { .. } => &state.bandcamp_image_upstream,
+        _ => &state.upstream,
+    };
+    let href = match link {
+        MusicLink::Bandcamp { url } => {
+            let cache_key = RenderCache::key(&["bandcamp", url]);
+            cached_or_resolve(
+                state,
+                &cache_key,
+                resolve_bandcamp_artwork(&state.bandcamp_upstream, url),
+            )
+            .await?
+        }
+        MusicLink::SoundCloud { artist, title } => {
+            let cache_key = RenderCache::key(&["soundcloud", artist, title]);
+            cached_or_resolve(
+                state,
+                &cache_key,
+                resolve_soundcloud_artwork(&state.upstream, artist, title),
+            )
+            .await?
+        }
+        MusicLink::Spotify { kind, id } => {
+            let kind = kind.to_ascii_lowercase();
+            let cache_key = RenderCache::key(&["spotify", &kind, id]);
+            cached_or_resolve(
+                state,
+                &cache_key,
+                resolve_spotify_artwork(&state.upstream, &kind, id),
+            )
+            .await?
+        }
+        MusicLink::AppleMusic { storefront, id } => {
+            let storefront = storefront.to_ascii_lowercase();
+            let cache_key = RenderCache::key(&["apple", &storefront, id]);
+            let href = cached_or_resolve(
+                state,
+                &cache_key,
+                resolve_apple_artwork(&state.upstream, &storefront, id),
+            )
+            .await?;
+            sized_artwork_url(&href, APPLE_DEFAULT_SIZE)
+        }
+        MusicLink::YouTube { id } => {
+            check_ytm_id(id)?;
+            return decode_ytm_thumbnail(&fetch_ytm_thumbnail(&state.upstream, id).await?);
+        }
+    };
+    fetch::fetch_image(upstream, &href)
         .await
-        .unwrap();
+        .map_err(|err| AppError::upstream_any(&err, "Failed to fetch image"))
+}
 
-    let metadata: PlausibleMetadata = og_headers.into();
-    let event = PlausibleEvent::default()
-        .with_url(format!("/music/ytm/{}", request.id))
-        .with_props(serde_json::json!({
-            "success": req.status().is_success().to_string(),
-        }));
-    report_plausible_event(state, event, metadata).await;
+/// `/palette` routes, the thumbnail routes with `mode=palette`.
+pub async fn handle_bandcamp_palette(
+    query: Query<BandcampRequest>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    handle_bandcamp_thumb(
+        query,
+        Query(TransformQuery::palette()),
+        Query(OutputQuery::default()),
+        State(state),
+        og_headers,
+    )
+    .await
+}
 
-    let mut headers = HeaderMap::new();
+pub async fn handle_soundcloud_palette(
+    request: Path<SoundcloudRequest>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    handle_soundcloud_thumb(
+        request,
+        Query(TransformQuery::palette()),
+        Query(OutputQuery::default()),
+        State(state),
+        og_headers,
+    )
+    .await
+}
 
-    if !req.status().is_success() {
-        if req.status() == reqwest::StatusCode::NOT_FOUND {
-            return (
-                StatusCode::NOT_FOUND,
-                headers,
-                format!("YouTube Music track not found: `{}'", request.id)
-                    .as_bytes()
-                    .to_vec(),
-            );
+pub async fn handle_spotify_palette(
+    request: Path<SpotifyRequest>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    handle_spotify_thumb(
+        request,
+        Query(TransformQuery::palette()),
+        Query(OutputQuery::default()),
+        State(state),
+        og_headers,
+    )
+    .await
+}
+
+pub async fn handle_apple_music_palette(
+    request: Path<AppleMusicRequest>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    handle_apple_music_thumb(
+        request,
+        Query(TransformQuery::palette()),
+        Query(OutputQuery::default()),
+        State(state),
+        og_headers,
+    )
+    .await
+}
+
+pub async fn handle_youtube_music_palette(
+    request: Path<YTMRequest>,
+    State(state): State<AppState>,
+    og_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    serve_ytm_palette(state, &request.id, og_headers).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::IpAddr,
+        sync::{Arc, OnceLock},
+    };
+
+    use axum::{response::Html, routing::get, Json, Router};
+
+    use super::*;
+    use crate::analytics::{MemorySink, NoopSink};
+
+    /// Stand-in for open.spotify.com, the track ID picks the scenario.
+    async fn oembed(Query(query): Query<HashMap<String, String>>) -> Response {
+        let url = query.get("url").cloned().unwrap_or_default();
+        match url.rsplit('/').next().unwrap_or_default() {
+            "found" => Json(serde_json::json!({
+                "thumbnail_url": "https://i.scdn.co/image/oembed",
+            }))
+            .into_response(),
+            "unknown" => StatusCode::NOT_FOUND.into_response(),
+            "malformed" => StatusCode::BAD_REQUEST.into_response(),
+            _ => Json(serde_json::json!({ "title": "No artwork" })).into_response(),
         }
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
+    }
+
+    async fn page(Path((_, id)): Path<(String, String)>) -> Response {
+        match id.as_str() {
+            "pageonly" => Html(
+                r#"<html><head><meta property="og:image" content="https://i.scdn.co/image/page"></head></html>"#,
+            )
+            .into_response(),
+            _ => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    /// Start the stand-in once on its own runtime, every test runtime is short lived.
+    fn spotify_stand_in() {
+        static BASE_URL: OnceLock<String> = OnceLock::new();
+        BASE_URL.get_or_init(|| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            std::thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new().unwrap();
+                runtime.block_on(async move {
+                    let app = Router::new()
+                        .route("/oembed", get(oembed))
+                        .route("/:kind/:id", get(page));
+                    let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                    axum::serve(listener, app).await.unwrap();
+                });
+            });
+            std::env::set_var("SPOTIFY_BASE_URL", &base_url);
+            base_url
+        });
+    }
+
+    async fn spotify_thumb(id: &str) -> Result<Response, AppError> {
+        spotify_thumb_with(
+            AppState::for_tests(Arc::new(NoopSink)),
+            id,
+            HeaderMap::new(),
+        )
+        .await
+    }
+
+    async fn spotify_thumb_with(
+        state: AppState,
+        id: &str,
+        headers: HeaderMap,
+    ) -> Result<Response, AppError> {
+        spotify_stand_in();
+        handle_spotify_thumb(
+            Path(SpotifyRequest {
+                kind: "track".to_string(),
+                id: id.to_string(),
+            }),
+            Query(TransformQuery::default()),
+            Query(OutputQuery::default()),
+            State(state),
             headers,
-            "Failed to fetch URL".as_bytes().to_vec(),
-        );
+        )
+        .await
+    }
+
+    fn location(response: &Response) -> &str {
+        response.headers()[header::LOCATION].to_str().unwrap()
     }
 
-    let image_data = req.bytes().await.unwrap().to_vec();
-    let cropped_image = create_ytm_thumb_square(image_data);
-
-    let mut buf = Cursor::new(Vec::new());
-    match cropped_image.write_to(&mut buf, image::ImageFormat::Png) {
-        Ok(_) => {
-            buf.set_position(0);
-            let data = buf.get_ref().to_vec();
-
-            headers.insert(header::CONTENT_TYPE, "image/png".parse().unwrap());
-            headers.insert(
-                header::CONTENT_DISPOSITION,
-                format!("inline; filename=\"{}.thumb.png\"", request.id)
-                    .parse()
-                    .unwrap(),
-            );
-            // Add cache control similar to what Youtube does.
-            headers.insert(
-                header::CACHE_CONTROL,
-                "public, max-age=7200".parse().unwrap(),
-            );
-            (StatusCode::OK, headers, data)
+    #[test]
+    fn ytm_ids_are_validated() {
+        assert!(check_ytm_id("dQw4w9WgXcQ").is_ok());
+        assert!(check_ytm_id("a_b-c").is_ok());
+        for id in ["", "a\nb", "a/b", "a b", &"a".repeat(65)] {
+            assert!(check_ytm_id(id).is_err(), "{:?}", id);
         }
-        Err(err) => {
-            tracing::error!("Error writing cropped image to buffer: {}", err);
-            headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                headers,
-                "Error writing cropped image to buffer".as_bytes().to_vec(),
-            )
+    }
+
+    #[test]
+    fn bandcamp_images_only_come_from_bandcamp() {
+        let state = AppState::for_tests(Arc::new(NoopSink));
+        let check = |url: &str| {
+            state
+                .bandcamp_image_upstream
+                .check(&url.parse().unwrap())
+                .is_ok()
+        };
+        assert!(check("https://f4.bcbits.com/img/a10_16.jpg"));
+        assert!(check("https://artist.bandcamp.com/cover.jpg"));
+        assert!(!check("http://f4.bcbits.com/img/a10_16.jpg"));
+        assert!(!check("https://169.254.169.254/latest/meta-data"));
+        assert!(!check("https://example.com/cover.jpg"));
+    }
+
+    #[tokio::test]
+    async fn spotify_redirects_to_oembed_thumbnail() {
+        let response = spotify_thumb("found").await.unwrap();
+        assert!(response.status().is_redirection());
+        assert_eq!(location(&response), "https://i.scdn.co/image/oembed");
+    }
+
+    #[tokio::test]
+    async fn spotify_falls_back_to_og_image() {
+        let response = spotify_thumb("pageonly").await.unwrap();
+        assert!(response.status().is_redirection());
+        assert_eq!(location(&response), "https://i.scdn.co/image/page");
+    }
+
+    #[tokio::test]
+    async fn spotify_unknown_and_malformed_ids_are_not_found() {
+        for id in ["unknown", "malformed"] {
+            let err = spotify_thumb(id).await.unwrap_err();
+            assert_eq!(err.status(), StatusCode::NOT_FOUND, "{}", id);
         }
     }
+
+    #[tokio::test]
+    async fn spotify_page_without_artwork_is_not_found() {
+        let err = spotify_thumb("gone").await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn spotify_requests_are_reported() {
+        let sink = Arc::new(MemorySink::default());
+        let state = AppState::for_tests(sink.clone());
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, "TestBot/1.0".parse().unwrap());
+        headers.insert("x-real-ip", "203.0.113.7".parse().unwrap());
+
+        spotify_thumb_with(state.clone(), "found", headers)
+            .await
+            .unwrap();
+
+        let events = sink.take(&state).await;
+        assert_eq!(events.len(), 1);
+        let (event, metadata) = &events[0];
+        assert_eq!(event.name, "pageview");
+        assert_eq!(event.url, "/music/spotify/track/found");
+        assert_eq!(event.props, Some(serde_json::json!({ "success": "true" })));
+        assert_eq!(metadata.user_agent, "TestBot/1.0");
+        assert_eq!(
+            metadata.ip_address,
+            vec!["203.0.113.7".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn spotify_failures_are_reported() {
+        let sink = Arc::new(MemorySink::default());
+        let state = AppState::for_tests(sink.clone());
+
+        let err = spotify_thumb_with(state.clone(), "unknown", HeaderMap::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+
+        let events = sink.take(&state).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0.url, "/music/spotify/track/unknown");
+        assert_eq!(
+            events[0].0.props,
+            Some(serde_json::json!({ "success": "false" }))
+        );
+    }
 }
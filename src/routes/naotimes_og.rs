@@ -1,153 +1,415 @@
 /// OG Image Generator for naoTimes
+use std::borrow::Cow;
+
 use axum::{
     extract::{Query, State},
-    http::{header, HeaderMap, StatusCode},
-    response::IntoResponse,
+    http::HeaderMap,
+    response::Response,
 };
-use og_image_writer::{style, writer::OGImageWriter, TextArea};
+use fluent_bundle::FluentArgs;
+use image::{DynamicImage, RgbaImage};
+use og_image_writer::{img::ImageInputFormat, style, writer::OGImageWriter, TextArea};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use tokio::task;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::{report_plausible_event, AppState, PlausibleEvent};
+use crate::{
+    cache::{self, Card, Prepared, RenderCache, RenderOutput},
+    encoding, fetch,
+    i18n::Localizer,
+    imaging,
+    prelude::AppError,
+    signing,
+    theme::{self, Theme},
+    AppState,
+};
 
-static IMAGE_BASE: &[u8] = include_bytes!("../../assets/ntui_base.png");
-static NOTO_SANS_BOLD: &[u8] = include_bytes!("../../assets/NotoSansCJK-Bold.ttc");
-static NOTO_SANS_LIGHT: &[u8] = include_bytes!("../../assets/NotoSans-Light.ttf");
+/// Size of the base artwork, every layout value below is tuned for it.
+const BASE_WIDTH: u32 = 1280;
+const BASE_HEIGHT: u32 = 720;
+/// Bounds for custom `w`/`h` values.
+const MIN_DIMENSION: u32 = 200;
+const MAX_DIMENSION: u32 = 2400;
+/// Icon size and its center relative to the canvas center, covering the logo of the base artwork.
+const ICON_DIAMETER: f32 = 280.;
+const ICON_OFFSET_Y: f32 = -101.;
+const ICON_RING_WIDTH: f32 = 8.;
+const MAX_ICON_BYTES: usize = 2 * 1024 * 1024;
+/// Progress bar and percentage label size in base pixels.
+const PROGRESS_WIDTH: f32 = 420.;
+const PROGRESS_HEIGHT: f32 = 14.;
+const PROGRESS_LABEL_WIDTH: f32 = 80.;
+const PROGRESS_GAP: f32 = 16.;
+/// Hosts icons may be loaded from unless `ICON_ALLOWED_HOSTS` says otherwise.
+pub const DEFAULT_ICON_HOSTS: [&str; 2] = ["cdn.discordapp.com", "media.discordapp.net"];
+/// Background colors of generated icons, picked by the server name.
+static FALLBACK_ICON_COLORS: [[u8; 4]; 6] = [
+    [88, 101, 242, 255],
+    [117, 126, 140, 255],
+    [59, 165, 93, 255],
+    [250, 166, 26, 255],
+    [237, 66, 69, 255],
+    [235, 69, 158, 255],
+];
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct OGImageRequest {
     name: String,
     count: Option<usize>,
     total: Option<usize>,
+    /// URL of the server icon, drawn over the logo.
+    icon: Option<String>,
+    /// Color of the ring around the icon, `#RRGGBB` or `#RRGGBBAA`.
+    ring: Option<String>,
+    /// Draw a progress bar of finished projects, needs both `count` and `total`.
+    progress: Option<bool>,
+    /// Return the color palette of the card as JSON instead of the image.
+    palette: Option<bool>,
+    /// Theme name, see [`crate::theme`].
+    theme: Option<String>,
+    /// Card language, negotiated from `Accept-Language` when missing.
+    lang: Option<String>,
+    /// Size preset, see [`CardSize::from_preset`].
+    preset: Option<String>,
+    /// Custom width, overrides the preset width.
+    w: Option<u32>,
+    /// Custom height, overrides the preset height.
+    h: Option<u32>,
+    /// Output format, negotiated from the `Accept` header when missing.
+    format: Option<String>,
+    /// Quality of lossy output formats, 1-100.
+    quality: Option<u8>,
+    /// HMAC signature of the other parameters, see [`crate::signing`].
+    #[serde(skip_serializing)]
+    sig: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CardSize {
+    pub const DEFAULT: CardSize = CardSize {
+        width: BASE_WIDTH,
+        height: BASE_HEIGHT,
+    };
+
+    pub fn from_preset(preset: &str) -> Option<Self> {
+        let (width, height) = match preset.to_ascii_lowercase().as_str() {
+            "default" | "og" | "discord" => (BASE_WIDTH, BASE_HEIGHT),
+            // summary_large_image
+            "twitter" | "x" => (1200, 630),
+            "square" => (1080, 1080),
+            "story" => (1080, 1920),
+            _ => return None,
+        };
+        Some(CardSize { width, height })
+    }
+
+    /// Resolve the canvas size from the `preset`, `w` and `h` parameters.
+    pub fn resolve(preset: Option<&str>, w: Option<u32>, h: Option<u32>) -> Result<Self, String> {
+        let base = match preset {
+            Some(preset) => CardSize::from_preset(preset)
+                .ok_or_else(|| format!("Unknown size preset: `{}`", preset))?,
+            None => CardSize::DEFAULT,
+        };
+        let size = CardSize {
+            width: w.unwrap_or(base.width),
+            height: h.unwrap_or(base.height),
+        };
+        let valid = MIN_DIMENSION..=MAX_DIMENSION;
+        if !valid.contains(&size.width) || !valid.contains(&size.height) {
+            return Err(format!(
+                "Image size must be between {} and {} pixels, got {}x{}",
+                MIN_DIMENSION, MAX_DIMENSION, size.width, size.height
+            ));
+        }
+        Ok(size)
+    }
+
+    /// Scale factor relative to the base artwork, limited by the tighter axis.
+    pub fn scale(&self) -> f32 {
+        (self.width as f32 / BASE_WIDTH as f32).min(self.height as f32 / BASE_HEIGHT as f32)
+    }
+
+    /// Scale factor of the cover-fitted artwork, limited by the looser axis.
+    pub fn cover_scale(&self) -> f32 {
+        (self.width as f32 / BASE_WIDTH as f32).max(self.height as f32 / BASE_HEIGHT as f32)
+    }
+
+    fn px(&self, value: f32) -> i32 {
+        (value * self.scale()).round() as i32
+    }
+
+    /// Like [`CardSize::px`] but for offsets that must clear the artwork.
+    fn cover_px(&self, value: f32) -> i32 {
+        (value * self.cover_scale()).round() as i32
+    }
+
+    fn font(&self, size: f32) -> f32 {
+        size * self.scale()
+    }
+
+    /// Diameter of the icon, capped by the canvas since the looser axis can exceed it.
+    fn icon_diameter(&self) -> u32 {
+        (self.cover_px(ICON_DIAMETER) as u32).min(self.width.min(self.height))
+    }
+}
+
+/// Create the theme background for the requested size, cover-fitting the artwork.
+fn create_background(
+    theme: &Theme,
+    size: CardSize,
+) -> anyhow::Result<(Cow<'_, [u8]>, ImageInputFormat)> {
+    if theme.background_size == (size.width, size.height) {
+        let format = match theme.background_format {
+            image::ImageFormat::Jpeg => ImageInputFormat::Jpeg,
+            _ => ImageInputFormat::Png,
+        };
+        return Ok((Cow::Borrowed(&theme.background), format));
+    }
+    let base = image::load_from_memory(&theme.background)?;
+    let fitted = imaging::cover_fit(&base, size.width, size.height);
+    let data = encoding::encode_image(&fitted, encoding::OutputFormat::Png, None)?;
+    Ok((Cow::Owned(data), ImageInputFormat::Png))
+}
+
+/// Generate an icon from the first letter of the server name, used when the icon fails to load.
+fn create_fallback_icon(name: &str, diameter: u32, theme: &Theme) -> anyhow::Result<RgbaImage> {
+    let hash = name.bytes().fold(0usize, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte as usize)
+    });
+    let background = FALLBACK_ICON_COLORS[hash % FALLBACK_ICON_COLORS.len()];
+    let initial = name
+        .chars()
+        .find(|c| !c.is_whitespace())
+        .map(|c| c.to_uppercase().to_string())
+        .unwrap_or_else(|| "?".to_string());
+
+    let mut writer = OGImageWriter::new(style::WindowStyle {
+        width: diameter,
+        height: diameter,
+        background_color: Some(style::Rgba(background)),
+        align_items: style::AlignItems::Center,
+        justify_content: style::JustifyContent::Center,
+        ..style::WindowStyle::default()
+    })?;
+    writer.set_text(
+        &initial,
+        style::Style {
+            font_size: diameter as f32 * 0.45,
+            color: style::Rgba([255, 255, 255, 255]),
+            ..style::Style::default()
+        },
+        Some(theme.font_bold.to_vec()),
+    )?;
+    writer.paint()?;
+    RgbaImage::from_raw(diameter, diameter, writer.into_vec()?)
+        .ok_or_else(|| anyhow::anyhow!("Painted icon does not match its dimensions"))
+}
+
+/// Circle-mask the icon, or a generated one when it could not be loaded.
+fn create_icon(
+    name: &str,
+    icon: Option<&DynamicImage>,
+    ring: Option<[u8; 4]>,
+    size: CardSize,
+    theme: &Theme,
+) -> anyhow::Result<RgbaImage> {
+    let diameter = size.icon_diameter();
+    let icon = match icon {
+        Some(icon) => icon.clone(),
+        None => DynamicImage::ImageRgba8(create_fallback_icon(name, diameter, theme)?),
+    };
+    let ring = ring.map(|color| (color, ICON_RING_WIDTH * size.cover_scale()));
+    Ok(imaging::circle_crop(&icon, diameter, ring))
 }
 
 fn create_og_image(
-    uuid: String,
-    name: String,
-    count: Option<usize>,
-    total: Option<usize>,
-) -> anyhow::Result<Vec<u8>> {
+    uuid: &str,
+    request: &OGImageRequest,
+    size: CardSize,
+    icon: Option<&RgbaImage>,
+    theme: &Theme,
+    text: &Localizer,
+) -> anyhow::Result<RgbaImage> {
+    let (name, count, total) = (&request.name, request.count, request.total);
+    let (background, background_format) = create_background(theme, size)?;
     let mut writer = OGImageWriter::from_data(
         style::WindowStyle {
             align_items: style::AlignItems::Center,
             justify_content: style::JustifyContent::Center,
-            width: 1280,
-            height: 720,
+            width: size.width,
+            height: size.height,
             flex_direction: style::FlexDirection::Column,
             ..style::WindowStyle::default()
         },
-        IMAGE_BASE,
-        og_image_writer::img::ImageInputFormat::Png,
+        &background,
+        background_format,
     )?;
 
-    let mut margin_t = 100;
+    if let Some(icon) = icon {
+        let diameter = icon.width();
+        let center_y = size.height as i32 / 2 + size.cover_px(ICON_OFFSET_Y);
+        writer.set_img_with_data(
+            &encoding::encode_png(icon)?,
+            diameter,
+            diameter,
+            ImageInputFormat::Png,
+            style::Style {
+                position: style::Position::Absolute,
+                top: Some(center_y - diameter as i32 / 2),
+                left: Some((size.width as i32 - diameter as i32) / 2),
+                ..style::Style::default()
+            },
+        )?;
+    }
+
+    let mut margin_t = 100.;
     if count.is_some() && total.is_some() {
-        margin_t += 70;
+        margin_t += 70.;
     } else if count.is_some() || total.is_some() {
-        margin_t += 44;
+        margin_t += 44.;
+    }
+    // The icon is a bit larger than the logo it covers, keep the name clear of it.
+    if icon.is_some() {
+        margin_t += 24.;
     }
 
+    // `count` is what is left of `total`, the bar shows the finished part.
+    let progress = match (request.progress, count, total) {
+        (Some(true), Some(count), Some(total)) if total > 0 => {
+            Some(total.saturating_sub(count) as f32 / total as f32)
+        }
+        _ => None,
+    };
+    // The content is centered, so the text ends half its height below the middle.
+    let progress_bar = progress.map(|progress| {
+        let text_bottom = (size.cover_px(margin_t) + size.px(100.)) / 2;
+        let bar_height = size.px(PROGRESS_HEIGHT) as u32;
+        let bar_width = size.px(PROGRESS_WIDTH) as u32;
+        let group_width = bar_width + size.px(PROGRESS_GAP + PROGRESS_LABEL_WIDTH) as u32;
+        let x = size.width.saturating_sub(group_width) / 2;
+        let y = (size.height as i32 / 2 + text_bottom + size.px(40.)) as u32;
+        (progress, x, y, bar_width, bar_height)
+    });
+    if let Some((progress, x, y, bar_width, bar_height)) = progress_bar {
+        let font_size = size.font(22.);
+        writer.set_text(
+            &format!("{}%", (progress * 100.).round()),
+            style::Style {
+                font_size,
+                color: theme.text_color(),
+                position: style::Position::Absolute,
+                top: Some((y + bar_height / 2) as i32 - (font_size * 0.55).round() as i32),
+                left: Some((x + bar_width) as i32 + size.px(PROGRESS_GAP)),
+                ..style::Style::default()
+            },
+            Some(theme.font_bold.to_vec()),
+        )?;
+    }
+
+    let margin_x = size.px(100.);
+    let max_width = Some(size.width.saturating_sub(size.px(120.) as u32));
+
     writer.set_text(
         name.as_str(),
         style::Style {
-            font_size: 40.,
-            color: style::Rgba([255, 255, 255, 255]),
+            font_size: size.font(40.),
+            color: theme.text_color(),
             text_align: style::TextAlign::Center,
             word_break: style::WordBreak::BreakAll,
-            margin: style::Margin(margin_t, 100, 0, 100),
-            max_width: Some(1160),
+            margin: style::Margin(size.cover_px(margin_t), margin_x, 0, margin_x),
+            max_width,
             ..style::Style::default()
         },
-        Some(NOTO_SANS_BOLD.to_vec()),
+        Some(theme.font_bold.to_vec()),
     )?;
 
-    if let Some(count) = count {
-        let text_data = match count {
-            0 => "\nTidak ada utang".to_string(),
-            _ => "\nSisa utang: ".to_string(),
-        };
-        let mut textarea = TextArea::new();
-        textarea.push_text(&text_data);
-        if count > 0 {
-            textarea.push(
-                format!("{} utang", count).as_str(),
-                style::Style {
-                    font_size: 24.,
-                    color: style::Rgba([255, 255, 255, 255]),
-                    ..style::Style::default()
-                },
-                Some(NOTO_SANS_BOLD.to_vec()),
-            )?;
-        }
-        writer.set_textarea(
-            textarea,
-            style::Style {
-                margin: style::Margin(30, 100, 0, 100),
-                font_size: 24.,
-                color: style::Rgba([255, 255, 255, 255]),
-                text_align: style::TextAlign::Center,
-                word_break: style::WordBreak::BreakAll,
-                max_width: Some(1160),
-                line_height: 2.5,
-                ..style::Style::default()
-            },
-            Some(NOTO_SANS_LIGHT.to_vec()),
-        )?;
-    }
+    // The light font has no CJK glyphs, use the bold one for those languages.
+    let font_light = if text.lang.cjk {
+        &theme.font_bold
+    } else {
+        &theme.font_light
+    };
 
-    if let Some(total) = total {
-        let text_data = match total {
-            0 => "\nTidak ada garapan".to_string(),
-            _ => "\nProyek: ".to_string(),
+    let stats = [
+        (
+            count,
+            "count",
+            "card-debt-none",
+            "card-debt-label",
+            "card-debt-count",
+        ),
+        (
+            total,
+            "total",
+            "card-project-none",
+            "card-project-label",
+            "card-project-count",
+        ),
+    ];
+    let mut is_first = true;
+    for (value, arg, none_id, label_id, count_id) in stats {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
         };
         let mut textarea = TextArea::new();
-        textarea.push_text(&text_data);
-        if total > 0 {
+        if value == 0 {
+            textarea.push_text(&format!("\n{}", text.message(none_id, None)));
+        } else {
+            let mut args = FluentArgs::new();
+            args.set(arg, value);
+            textarea.push_text(&format!("\n{} ", text.message(label_id, None)));
             textarea.push(
-                format!("{} garapan", total).as_str(),
+                &text.message(count_id, Some(&args)),
                 style::Style {
-                    font_size: 24.,
-                    color: style::Rgba([255, 255, 255, 255]),
+                    font_size: size.font(24.),
+                    color: theme.text_color(),
                     ..style::Style::default()
                 },
-                Some(NOTO_SANS_BOLD.to_vec()),
+                Some(theme.font_bold.to_vec()),
             )?;
         }
-        let margin_t2 = if count.is_some() { 12 } else { 30 };
+        let margin_top = if is_first { 30. } else { 12. };
+        is_first = false;
         writer.set_textarea(
             textarea,
             style::Style {
-                margin: style::Margin(margin_t2, 100, 0, 100),
-                font_size: 24.,
-                color: style::Rgba([255, 255, 255, 255]),
+                margin: style::Margin(size.px(margin_top), margin_x, 0, margin_x),
+                font_size: size.font(24.),
+                color: theme.text_color(),
                 text_align: style::TextAlign::Center,
                 word_break: style::WordBreak::BreakAll,
-                max_width: Some(1160),
+                max_width,
                 line_height: 2.5,
                 ..style::Style::default()
             },
-            Some(NOTO_SANS_LIGHT.to_vec()),
+            Some(font_light.to_vec()),
         )?;
     }
 
     // Footer
+    let footer_margin = size.px(30.);
     let mut footer = TextArea::new();
     footer.push(
-        "naoTimes",
+        &theme.footer_text,
         style::Style {
-            color: style::Rgba([255, 255, 255, 255]),
-            font_size: 28.,
+            color: theme.brand_color(),
+            font_size: size.font(28.),
             ..style::Style::default()
         },
-        Some(NOTO_SANS_BOLD.to_vec()),
+        Some(theme.font_bold.to_vec()),
     )?;
     writer.set_textarea(
         footer,
         style::Style {
-            margin: style::Margin(30, 30, 30, 30),
-            font_size: 28.,
-            color: style::Rgba([255, 255, 255, 128]),
+            margin: style::Margin(footer_margin, footer_margin, footer_margin, footer_margin),
+            font_size: size.font(28.),
+            color: theme.footer_color(),
             text_align: style::TextAlign::End,
             right: Some(0),
             bottom: Some(0),
@@ -155,80 +417,175 @@ fn create_og_image(
             word_break: style::WordBreak::Normal,
             ..style::Style::default()
         },
-        Some(NOTO_SANS_LIGHT.to_vec()),
+        Some(theme.font_light.to_vec()),
     )?;
 
-    info!("Painting: {}", uuid);
+    info!("Painting: {} (theme: {})", uuid, theme.name);
     writer.paint()?;
-    Ok(writer.encode(og_image_writer::ImageOutputFormat::Png)?)
+    let (width, height) = (writer.width(), writer.height());
+    // og_image_writer uses an older version of `image`, so move over the raw pixels.
+    let mut image = RgbaImage::from_raw(width, height, writer.into_vec()?)
+        .ok_or_else(|| anyhow::anyhow!("Painted image does not match its dimensions"))?;
+
+    if let Some((progress, x, y, bar_width, bar_height)) = progress_bar {
+        imaging::draw_progress_bar(
+            &mut image,
+            x,
+            y,
+            bar_width,
+            bar_height,
+            progress,
+            theme.colors.progress,
+            theme.colors.progress_track,
+        );
+    }
+    Ok(image)
 }
 
 pub async fn handle_og_image_request(
     State(state): State<AppState>,
     headers: HeaderMap,
     og_request: Query<OGImageRequest>,
-) -> impl IntoResponse {
-    let name = og_request.name.clone();
-    let count = og_request.count;
-    let total = og_request.total;
+) -> Result<Response, AppError> {
+    if !signing::is_authorized(
+        state.signing_secret.as_deref(),
+        &og_request.0,
+        og_request.sig.as_deref(),
+    ) {
+        return Err(AppError::Forbidden(
+            "Invalid or missing signature".to_string(),
+        ));
+    }
 
-    let formatted = serde_qs::to_string(&og_request.0).unwrap_or_default();
+    let negotiated = encoding::negotiate(og_request.format.as_deref(), &headers)
+        .map_err(AppError::BadRequest)?;
 
-    let uuid = uuid::Uuid::new_v4().to_string();
+    let size = CardSize::resolve(og_request.preset.as_deref(), og_request.w, og_request.h)
+        .map_err(AppError::BadRequest)?;
 
-    let res = task::spawn_blocking(move || {
-        info!(
-            "Generating OG Image for {} with data: {:?}",
-            uuid.clone(),
-            og_request
-        );
-        let data = create_og_image(uuid.clone(), name, count, total);
+    let theme = state
+        .themes
+        .get(og_request.theme.as_deref())
+        .ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "Unknown theme: `{}`",
+                og_request.theme.as_deref().unwrap_or_default()
+            ))
+        })?;
 
-        match data {
-            Ok(data) => (data, uuid.clone()),
-            Err(err) => {
-                tracing::error!("Error creating OG Image: {}", err);
-                (Vec::new(), uuid.clone())
-            }
-        }
-    })
-    .await;
-
-    let errors_text = "Error creating OG Image".as_bytes().to_vec();
-    let mut resp_headers = HeaderMap::new();
-    match res {
-        Ok((data, uuid)) => {
-            let ev_metadata: crate::PlausibleMetadata = headers.into();
-            let event = PlausibleEvent::default()
-                .with_url(format!("/large?{}", formatted))
-                .with_props(serde_json::json!({
-                    "uuid": uuid.clone(),
-                }));
-            report_plausible_event(state, event, ev_metadata).await;
-
-            if data.is_empty() {
-                resp_headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
-                (StatusCode::INTERNAL_SERVER_ERROR, resp_headers, errors_text)
+    let negotiated_lang = state
+        .i18n
+        .negotiate(og_request.lang.as_deref(), &headers)
+        .map_err(AppError::BadRequest)?;
+
+    if let Some(icon) = og_request.icon.as_deref() {
+        let url = Url::parse(icon)
+            .map_err(|_| AppError::BadRequest(format!("Invalid icon URL: `{}`", icon)))?;
+        state
+            .icon_upstream
+            .check(&url)
+            .map_err(|blocked| AppError::BadRequest(format!("Icon refused: {}", blocked)))?;
+    }
+    let ring = theme::parse_color(og_request.ring.as_deref())
+        .map_err(|err| AppError::BadRequest(err.to_string()))?;
+    let as_palette = og_request.palette == Some(true);
+
+    let card = Card {
+        label: "large",
+        path: "/large",
+        description: "OG Image",
+        key: RenderCache::key(&[
+            "large",
+            &signing::canonical_query(&og_request.0),
+            if as_palette {
+                "json"
             } else {
-                resp_headers.insert(header::CONTENT_TYPE, "image/png".parse().unwrap());
-                resp_headers.insert(
-                    header::CONTENT_DISPOSITION,
-                    format!("inline; filename=\"{}.OGImage.png\"", uuid)
-                        .parse()
-                        .unwrap(),
-                );
-                // Add cache-control for 10 minutes
-                resp_headers.insert(
-                    header::CACHE_CONTROL,
-                    "public, max-age=600".parse().unwrap(),
-                );
-                (StatusCode::OK, resp_headers, data)
+                negotiated.format.extension()
+            },
+            negotiated_lang.lang.tag,
+            &theme.name,
+            &theme.fingerprint,
+        ]),
+        query: serde_qs::to_string(&og_request.0).unwrap_or_default(),
+        output: match as_palette {
+            true => RenderOutput::Palette,
+            false => RenderOutput::Image {
+                negotiated,
+                quality: og_request.quality,
+                name: "OGImage",
+            },
+        },
+        lang_from_header: negotiated_lang.used_header,
+    };
+
+    let icon_upstream = state.icon_upstream.clone();
+    let catalog = state.i18n.clone();
+    let prepare = async move {
+        // A broken icon should not break the card, a generated one is used instead.
+        let icon = match og_request.icon.as_deref() {
+            Some(url) => {
+                match fetch::fetch_image_limited(&icon_upstream, url, MAX_ICON_BYTES).await {
+                    Ok(icon) => Some(Some(icon)),
+                    Err(err) => {
+                        warn!("Failed to fetch icon {}: {}", url, err);
+                        Some(None)
+                    }
+                }
             }
+            None => None,
+        };
+        // Keep retrying the real icon instead of caching the generated one.
+        let cacheable = !matches!(icon, Some(None));
+        let draw = move |uuid: &str| {
+            info!(
+                "Generating OG Image for {} with data: {:?}",
+                uuid, og_request
+            );
+            let text = catalog.localizer(negotiated_lang.lang);
+            let icon = icon
+                .map(|icon| create_icon(&og_request.name, icon.as_ref(), ring, size, &theme))
+                .transpose()?;
+            create_og_image(uuid, &og_request, size, icon.as_ref(), &theme, &text)
+        };
+        Prepared { draw, cacheable }
+    };
+    cache::serve_render(state, headers, card, prepare).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(query: &str) -> RgbaImage {
+        let request: OGImageRequest = serde_qs::from_str(query).unwrap();
+        let size = CardSize::resolve(request.preset.as_deref(), request.w, request.h).unwrap();
+        let theme = Theme::builtin();
+        let catalog = crate::i18n::Catalog::load();
+        let text = catalog.localizer(catalog.find("en").unwrap());
+        let icon = create_icon(
+            &request.name,
+            None,
+            Some([255, 255, 255, 255]),
+            size,
+            &theme,
+        )
+        .unwrap();
+        create_og_image("test", &request, size, Some(&icon), &theme, &text).unwrap()
+    }
+
+    #[test]
+    fn icon_fits_extreme_aspect_ratios() {
+        for (width, height) in [(200, 2400), (2400, 200), (200, 1200), (1200, 200)] {
+            let size = CardSize { width, height };
+            assert!(size.icon_diameter() <= width.min(height));
         }
-        Err(err) => {
-            tracing::error!("Error creating OG Image: {}", err);
-            resp_headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
-            (StatusCode::INTERNAL_SERVER_ERROR, resp_headers, errors_text)
+        // Large canvases are slow to draw in debug builds, these overflowed all the same.
+        for (width, height) in [(200, 1200), (1200, 200)] {
+            let image = render(&format!(
+                "name=Test&count=3&total=5&progress=true&w={}&h={}",
+                width, height
+            ));
+            assert_eq!(image.dimensions(), (width, height));
         }
     }
 }
@@ -0,0 +1,313 @@
+/// Project progress card for a single show
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::Response,
+};
+use fluent_bundle::FluentArgs;
+use image::{DynamicImage, RgbaImage};
+use og_image_writer::{img::ImageInputFormat, style, writer::OGImageWriter};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    cache::{self, Card, Prepared, RenderCache, RenderOutput},
+    encoding, fetch,
+    i18n::Localizer,
+    imaging,
+    prelude::AppError,
+    signing,
+    theme::Theme,
+    AppState,
+};
+
+const CARD_WIDTH: u32 = 1280;
+const CARD_HEIGHT: u32 = 720;
+const COVER_WIDTH: u32 = 320;
+const COVER_HEIGHT: u32 = 450;
+const INFO_WIDTH: u32 = 720;
+const PILL_WIDTH: u32 = 84;
+const PILL_HEIGHT: u32 = 40;
+/// Hosts covers may be loaded from when `COVER_ALLOWED_HOSTS` is unset.
+pub const DEFAULT_COVER_HOSTS: [&str; 4] = [
+    "s4.anilist.co",
+    "cdn.myanimelist.net",
+    "cdn.discordapp.com",
+    "media.discordapp.net",
+];
+
+/// Staff roles in the order they are shown.
+static ROLES: [&str; 7] = ["TL", "TLC", "Enc", "ED", "TM", "TS", "QC"];
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ProjectCardRequest {
+    title: String,
+    /// URL of the cover art.
+    cover: Option<String>,
+    /// Current episode.
+    episode: Option<u32>,
+    /// Total episodes.
+    episodes: Option<u32>,
+    /// Comma separated finished roles, e.g. `TL,TLC,Enc`. Other roles are pending.
+    done: Option<String>,
+    /// Theme name, see [`crate::theme`].
+    theme: Option<String>,
+    /// Card language, negotiated from `Accept-Language` when missing.
+    lang: Option<String>,
+    /// Output format, negotiated from the `Accept` header when missing.
+    format: Option<String>,
+    /// Quality of lossy output formats, 1-100.
+    quality: Option<u8>,
+    /// HMAC signature of the other parameters, see [`crate::signing`].
+    #[serde(skip_serializing)]
+    sig: Option<String>,
+}
+
+impl ProjectCardRequest {
+    fn is_done(&self, role: &str) -> bool {
+        self.done
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .any(|done| done.trim().eq_ignore_ascii_case(role))
+    }
+}
+
+fn create_pill(role: &str, done: bool, theme: &Theme) -> anyhow::Result<OGImageWriter> {
+    let (background, color) = if done {
+        (theme.colors.done, style::Rgba([24, 25, 28, 255]))
+    } else {
+        (theme.colors.pending, theme.text_color())
+    };
+    let mut pill = OGImageWriter::new(style::WindowStyle {
+        width: PILL_WIDTH,
+        height: PILL_HEIGHT,
+        background_color: Some(style::Rgba(background)),
+        align_items: style::AlignItems::Center,
+        justify_content: style::JustifyContent::Center,
+        ..style::WindowStyle::default()
+    })?;
+    pill.set_text(
+        role,
+        style::Style {
+            font_size: 20.,
+            color,
+            ..style::Style::default()
+        },
+        Some(theme.font_bold.to_vec()),
+    )?;
+    Ok(pill)
+}
+
+fn create_project_card(
+    request: &ProjectCardRequest,
+    cover: Option<&DynamicImage>,
+    theme: &Theme,
+    text: &Localizer,
+) -> anyhow::Result<RgbaImage> {
+    let background = match cover {
+        Some(cover) => imaging::blurred_backdrop(cover, CARD_WIDTH, CARD_HEIGHT, 0.45),
+        None => {
+            let base = image::load_from_memory(&theme.background)?;
+            imaging::blurred_backdrop(&base, CARD_WIDTH, CARD_HEIGHT, 0.8)
+        }
+    };
+    let mut writer = OGImageWriter::from_data(
+        style::WindowStyle {
+            align_items: style::AlignItems::Center,
+            justify_content: style::JustifyContent::Center,
+            width: CARD_WIDTH,
+            height: CARD_HEIGHT,
+            flex_direction: style::FlexDirection::Row,
+            ..style::WindowStyle::default()
+        },
+        &encoding::encode_png(&background)?,
+        ImageInputFormat::Png,
+    )?;
+
+    if let Some(cover) = cover {
+        let cover = imaging::cover_fit(cover, COVER_WIDTH, COVER_HEIGHT);
+        writer.set_img_with_data(
+            &encoding::encode_png(&cover)?,
+            COVER_WIDTH,
+            COVER_HEIGHT,
+            ImageInputFormat::Png,
+            style::Style {
+                margin: style::Margin(0, 60, 0, 0),
+                border_radius: style::BorderRadius(16, 16, 16, 16),
+                ..style::Style::default()
+            },
+        )?;
+    }
+
+    // The light font has no CJK glyphs, use the bold one for those languages.
+    let font_light = if text.lang.cjk {
+        &theme.font_bold
+    } else {
+        &theme.font_light
+    };
+
+    let mut info = OGImageWriter::new(style::WindowStyle {
+        width: INFO_WIDTH,
+        height: COVER_HEIGHT,
+        align_items: style::AlignItems::Start,
+        justify_content: style::JustifyContent::Center,
+        flex_direction: style::FlexDirection::Column,
+        ..style::WindowStyle::default()
+    })?;
+    info.set_text(
+        &request.title,
+        style::Style {
+            font_size: 48.,
+            color: theme.text_color(),
+            word_break: style::WordBreak::BreakAll,
+            max_width: Some(INFO_WIDTH),
+            max_height: Some(200),
+            text_overflow: style::TextOverflow::Ellipsis,
+            ..style::Style::default()
+        },
+        Some(theme.font_bold.to_vec()),
+    )?;
+
+    if let Some(episode) = request.episode {
+        let mut args = FluentArgs::new();
+        args.set("episode", episode);
+        let message = match request.episodes {
+            Some(episodes) => {
+                args.set("episodes", episodes);
+                text.message("project-episode", Some(&args))
+            }
+            None => text.message("project-episode-single", Some(&args)),
+        };
+        info.set_text(
+            &message,
+            style::Style {
+                margin: style::Margin(16, 0, 0, 0),
+                font_size: 28.,
+                color: theme.text_color(),
+                ..style::Style::default()
+            },
+            Some(font_light.to_vec()),
+        )?;
+    }
+
+    let mut pills = OGImageWriter::new(style::WindowStyle {
+        width: INFO_WIDTH,
+        height: PILL_HEIGHT,
+        align_items: style::AlignItems::Center,
+        justify_content: style::JustifyContent::Start,
+        flex_direction: style::FlexDirection::Row,
+        ..style::WindowStyle::default()
+    })?;
+    for role in ROLES {
+        let mut pill = create_pill(role, request.is_done(role), theme)?;
+        let radius = PILL_HEIGHT / 2;
+        pills.set_container(
+            &mut pill,
+            style::Style {
+                margin: style::Margin(0, 12, 0, 0),
+                border_radius: style::BorderRadius(radius, radius, radius, radius),
+                ..style::Style::default()
+            },
+        )?;
+    }
+    info.set_container(
+        &mut pills,
+        style::Style {
+            margin: style::Margin(32, 0, 0, 0),
+            ..style::Style::default()
+        },
+    )?;
+
+    writer.set_container(&mut info, style::Style::default())?;
+
+    writer.paint()?;
+    let (width, height) = (writer.width(), writer.height());
+    // og_image_writer uses an older version of `image`, so move over the raw pixels.
+    RgbaImage::from_raw(width, height, writer.into_vec()?)
+        .ok_or_else(|| anyhow::anyhow!("Painted image does not match its dimensions"))
+}
+
+pub async fn handle_project_card(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    request: Query<ProjectCardRequest>,
+) -> Result<Response, AppError> {
+    if !signing::is_authorized(
+        state.signing_secret.as_deref(),
+        &request.0,
+        request.sig.as_deref(),
+    ) {
+        return Err(AppError::Forbidden(
+            "Invalid or missing signature".to_string(),
+        ));
+    }
+
+    let negotiated =
+        encoding::negotiate(request.format.as_deref(), &headers).map_err(AppError::BadRequest)?;
+    let negotiated_lang = state
+        .i18n
+        .negotiate(request.lang.as_deref(), &headers)
+        .map_err(AppError::BadRequest)?;
+    let theme = state.themes.get(request.theme.as_deref()).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "Unknown theme: `{}`",
+            request.theme.as_deref().unwrap_or_default()
+        ))
+    })?;
+    if let Some(cover) = request.cover.as_deref() {
+        let url = Url::parse(cover)
+            .map_err(|_| AppError::BadRequest(format!("Invalid cover URL: `{}`", cover)))?;
+        state
+            .cover_upstream
+            .check(&url)
+            .map_err(|blocked| AppError::BadRequest(format!("Cover refused: {}", blocked)))?;
+    }
+
+    let card = Card {
+        label: "project",
+        path: "/project",
+        description: "project card",
+        key: RenderCache::key(&[
+            "project",
+            &signing::canonical_query(&request.0),
+            negotiated.format.extension(),
+            negotiated_lang.lang.tag,
+            &theme.name,
+            &theme.fingerprint,
+        ]),
+        query: serde_qs::to_string(&request.0).unwrap_or_default(),
+        output: RenderOutput::Image {
+            negotiated,
+            quality: request.quality,
+            name: "ProjectCard",
+        },
+        lang_from_header: negotiated_lang.used_header,
+    };
+
+    let cover_upstream = state.cover_upstream.clone();
+    let catalog = state.i18n.clone();
+    let prepare = async move {
+        // A broken cover should not break the card, render without it instead.
+        let cover = match request.cover.as_deref() {
+            Some(url) => match fetch::fetch_image(&cover_upstream, url).await {
+                Ok(cover) => Some(cover),
+                Err(err) => {
+                    warn!("Failed to fetch project cover {}: {}", url, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        // Keep retrying the cover instead of caching the card drawn without it.
+        let cacheable = request.cover.is_none() || cover.is_some();
+        let draw = move |uuid: &str| {
+            info!("Generating project card {} with data: {:?}", uuid, request);
+            let text = catalog.localizer(negotiated_lang.lang);
+            create_project_card(&request, cover.as_ref(), &theme, &text)
+        };
+        Prepared { draw, cacheable }
+    };
+    cache::serve_render(state, headers, card, prepare).await
+}
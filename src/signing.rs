@@ -0,0 +1,204 @@
+/// HMAC signing for generated image URLs.
+///
+/// When `OG_SIGNING_SECRET` is set, requests must carry a `sig` query parameter
+/// containing the hex-encoded HMAC-SHA256 of the canonicalized request.
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use urlencoding::encode;
+
+use crate::{
+    env::get_env,
+    routes::{
+        music_nowplaying::NowPlayingRequest, naotimes_og::OGImageRequest,
+        project_card::ProjectCardRequest,
+    },
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Build the canonical query string of a request.
+///
+/// Keys are sorted, empty values are dropped and every value is percent-encoded,
+/// so the same request always produces the same string regardless of how the
+/// original URL was written.
+pub fn canonical_query<T: Serialize>(request: &T) -> String {
+    let value = serde_json::to_value(request).unwrap_or_default();
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => return String::new(),
+    };
+
+    // serde_json's map is a BTreeMap, so keys are already sorted.
+    object
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::Null => return None,
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            Some(format!("{}={}", encode(key), encode(&value)))
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+fn hmac_for(secret: &str, request: &impl Serialize) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(canonical_query(request).as_bytes());
+    mac
+}
+
+/// Sign a request, returning the hex-encoded signature.
+pub fn sign_request<T: Serialize>(secret: &str, request: &T) -> String {
+    hex::encode(hmac_for(secret, request).finalize().into_bytes())
+}
+
+/// Verify the hex-encoded signature of a request in constant time.
+pub fn verify_request<T: Serialize>(secret: &str, request: &T, signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(signature) => hmac_for(secret, request).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Check a request against the configured secret, any request passes when signing is disabled.
+pub fn is_authorized<T: Serialize>(
+    secret: Option<&str>,
+    request: &T,
+    signature: Option<&str>,
+) -> bool {
+    match (secret, signature) {
+        (None, _) => true,
+        (Some(secret), Some(signature)) => verify_request(secret, request, signature),
+        (Some(_), None) => false,
+    }
+}
+
+/// Create a full signed URL for the signed route at `path`, e.g. `/project`.
+pub fn signed_url<T: Serialize>(base_url: &str, path: &str, secret: &str, request: &T) -> String {
+    format!(
+        "{}{}?{}&sig={}",
+        base_url.trim_end_matches('/'),
+        path,
+        canonical_query(request),
+        sign_request(secret, request)
+    )
+}
+
+/// Base URL of this server, derived from `SERVER_HOSTNAME` and `SERVER_HTTPS`.
+fn server_base_url() -> String {
+    let hostname = get_env("SERVER_HOSTNAME").unwrap_or_else(|_| {
+        let host = get_env("HOST").unwrap_or("127.0.0.1".to_string());
+        let port = get_env("PORT").unwrap_or("12460".to_string());
+        format!("{}:{}", host, port)
+    });
+    let scheme = match get_env("SERVER_HTTPS").as_deref() {
+        Ok("true") | Ok("1") => "https",
+        _ => "http",
+    };
+    format!("{}://{}", scheme, hostname)
+}
+
+/// Entry point for `naotimes_open_graph sign [--base <url>] <query>`.
+///
+/// The query is the unsigned query string, e.g. `name=Foo&count=3&total=5` for `/large`,
+/// or prefixed with the path of another signed route, e.g. `/project?title=Foo`.
+pub fn run_cli(args: &[String]) -> anyhow::Result<()> {
+    let secret = get_env("OG_SIGNING_SECRET")
+        .map_err(|_| anyhow::anyhow!("OG_SIGNING_SECRET is not set"))?;
+
+    let mut base_url = None;
+    let mut query = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => base_url = args.next().cloned(),
+            _ => query = Some(arg.clone()),
+        }
+    }
+
+    let query = query.ok_or_else(|| anyhow::anyhow!("Usage: sign [--base <url>] <query>"))?;
+    let (path, query) = match query.split_once('?') {
+        Some((path, query)) if path.starts_with('/') => (path.to_string(), query.to_string()),
+        _ => (
+            "/large".to_string(),
+            query.trim_start_matches('?').to_string(),
+        ),
+    };
+    let base_url = base_url.unwrap_or_else(server_base_url);
+
+    let url = match path.as_str() {
+        "/large" => {
+            let request: OGImageRequest = serde_qs::from_str(&query)?;
+            signed_url(&base_url, &path, &secret, &request)
+        }
+        "/project" => {
+            let request: ProjectCardRequest = serde_qs::from_str(&query)?;
+            signed_url(&base_url, &path, &secret, &request)
+        }
+        "/music/nowplaying" => {
+            let request: NowPlayingRequest = serde_qs::from_str(&query)?;
+            signed_url(&base_url, &path, &secret, &request)
+        }
+        other => anyhow::bail!("`{}` does not take signed URLs", other),
+    };
+    println!("{}", url);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Request {
+        name: String,
+        count: Option<u32>,
+        total: Option<u32>,
+    }
+
+    fn request(name: &str, count: Option<u32>) -> Request {
+        Request {
+            name: name.to_string(),
+            count,
+            total: None,
+        }
+    }
+
+    #[test]
+    fn canonical_query_sorts_encodes_and_drops_empty_values() {
+        assert_eq!(
+            canonical_query(&request("naoTimes & co", Some(3))),
+            "count=3&name=naoTimes%20%26%20co"
+        );
+        assert_eq!(canonical_query(&request("a", None)), "name=a");
+    }
+
+    #[test]
+    fn verify_request_accepts_only_matching_signatures() {
+        let signature = sign_request("secret", &request("a", Some(3)));
+        assert!(verify_request("secret", &request("a", Some(3)), &signature));
+        assert!(!verify_request(
+            "secret",
+            &request("a", Some(4)),
+            &signature
+        ));
+        assert!(!verify_request("other", &request("a", Some(3)), &signature));
+        assert!(!verify_request("secret", &request("a", Some(3)), "not hex"));
+    }
+
+    #[test]
+    fn is_authorized_only_requires_a_signature_with_a_secret() {
+        let signature = sign_request("secret", &request("a", None));
+        assert!(is_authorized(None, &request("a", None), None));
+        assert!(is_authorized(
+            Some("secret"),
+            &request("a", None),
+            Some(&signature)
+        ));
+        assert!(!is_authorized(Some("secret"), &request("a", None), None));
+    }
+}
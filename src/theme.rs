@@ -0,0 +1,285 @@
+/// Card themes, loaded from disk with the original look built in as `default`.
+///
+/// A theme is a directory inside `THEMES_DIR` containing a `theme.toml` or `theme.json`
+/// manifest, e.g.:
+///
+/// ```toml
+/// background = "background.png"
+/// footer = "naoTimes"
+///
+/// [fonts]
+/// bold = "Bold.ttf"
+/// light = "Light.ttf"
+///
+/// [colors]
+/// text = "#ffffff"
+/// footer = "#ffffff80"
+/// brand = "#ffffff"
+/// done = "#57f287"
+/// pending = "#4f545c"
+/// progress = "#57f287"
+/// progress_track = "#ffffff30"
+/// ```
+///
+/// Every key except `background` is optional and falls back to the default theme.
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use og_image_writer::style;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+static IMAGE_BASE: &[u8] = include_bytes!("../assets/ntui_base.png");
+static NOTO_SANS_BOLD: &[u8] = include_bytes!("../assets/NotoSansCJK-Bold.ttc");
+static NOTO_SANS_LIGHT: &[u8] = include_bytes!("../assets/NotoSans-Light.ttf");
+
+pub const DEFAULT_THEME: &str = "default";
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThemeColors {
+    /// Server name and statistics.
+    pub text: [u8; 4],
+    /// Base color of the footer line.
+    pub footer: [u8; 4],
+    /// Footer brand text.
+    pub brand: [u8; 4],
+    /// Finished staff roles on the project card.
+    pub done: [u8; 4],
+    /// Unfinished staff roles on the project card.
+    pub pending: [u8; 4],
+    /// Finished part of the progress bar.
+    pub progress: [u8; 4],
+    /// Remaining part of the progress bar.
+    pub progress_track: [u8; 4],
+}
+
+pub struct Theme {
+    pub name: String,
+    /// Encoded background image, PNG or JPEG.
+    pub background: Cow<'static, [u8]>,
+    pub background_format: image::ImageFormat,
+    pub background_size: (u32, u32),
+    pub font_bold: Cow<'static, [u8]>,
+    pub font_light: Cow<'static, [u8]>,
+    pub colors: ThemeColors,
+    pub footer_text: String,
+    /// Hash of everything above, changes whenever the theme files change.
+    pub fingerprint: String,
+}
+
+impl Theme {
+    /// The original naoTimes look.
+    pub fn builtin() -> Self {
+        Theme {
+            name: DEFAULT_THEME.to_string(),
+            background: Cow::Borrowed(IMAGE_BASE),
+            background_format: image::ImageFormat::Png,
+            background_size: image_dimensions(IMAGE_BASE).expect("built-in background is valid"),
+            font_bold: Cow::Borrowed(NOTO_SANS_BOLD),
+            font_light: Cow::Borrowed(NOTO_SANS_LIGHT),
+            colors: ThemeColors {
+                text: [255, 255, 255, 255],
+                footer: [255, 255, 255, 128],
+                brand: [255, 255, 255, 255],
+                done: [87, 242, 135, 255],
+                pending: [79, 84, 92, 255],
+                progress: [87, 242, 135, 255],
+                progress_track: [255, 255, 255, 48],
+            },
+            footer_text: "naoTimes".to_string(),
+            fingerprint: String::new(),
+        }
+        .with_fingerprint()
+    }
+
+    fn with_fingerprint(mut self) -> Self {
+        let mut hasher = Sha256::new();
+        for part in [&self.background, &self.font_bold, &self.font_light] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        let colors = &self.colors;
+        for color in [
+            colors.text,
+            colors.footer,
+            colors.brand,
+            colors.done,
+            colors.pending,
+            colors.progress,
+            colors.progress_track,
+        ] {
+            hasher.update(color);
+        }
+        hasher.update(self.footer_text.as_bytes());
+        self.fingerprint = hex::encode(&hasher.finalize()[..8]);
+        self
+    }
+
+    pub fn text_color(&self) -> style::Rgba {
+        style::Rgba(self.colors.text)
+    }
+
+    pub fn footer_color(&self) -> style::Rgba {
+        style::Rgba(self.colors.footer)
+    }
+
+    pub fn brand_color(&self) -> style::Rgba {
+        style::Rgba(self.colors.brand)
+    }
+
+    fn load(name: &str, dir: &Path) -> anyhow::Result<Self> {
+        let manifest = ThemeManifest::read(dir)?;
+        let default = Theme::builtin();
+
+        let background = std::fs::read(dir.join(&manifest.background))?;
+        let format = image::guess_format(&background)?;
+        if !matches!(format, image::ImageFormat::Png | image::ImageFormat::Jpeg) {
+            anyhow::bail!("Background must be a PNG or JPEG image, got {:?}", format);
+        }
+
+        let read_font = |file: &Option<String>, fallback: Cow<'static, [u8]>| match file {
+            Some(file) => std::fs::read(dir.join(file)).map(Cow::Owned),
+            None => Ok(fallback),
+        };
+
+        let colors = &manifest.colors;
+        Ok(Theme {
+            name: name.to_string(),
+            background_size: image_dimensions(&background)?,
+            background: Cow::Owned(background),
+            background_format: format,
+            font_bold: read_font(&manifest.fonts.bold, default.font_bold)?,
+            font_light: read_font(&manifest.fonts.light, default.font_light)?,
+            colors: ThemeColors {
+                text: parse_color(colors.text.as_deref())?.unwrap_or(default.colors.text),
+                footer: parse_color(colors.footer.as_deref())?.unwrap_or(default.colors.footer),
+                brand: parse_color(colors.brand.as_deref())?.unwrap_or(default.colors.brand),
+                done: parse_color(colors.done.as_deref())?.unwrap_or(default.colors.done),
+                pending: parse_color(colors.pending.as_deref())?.unwrap_or(default.colors.pending),
+                progress: parse_color(colors.progress.as_deref())?
+                    .unwrap_or(default.colors.progress),
+                progress_track: parse_color(colors.progress_track.as_deref())?
+                    .unwrap_or(default.colors.progress_track),
+            },
+            footer_text: manifest.footer.unwrap_or(default.footer_text),
+            fingerprint: String::new(),
+        }
+        .with_fingerprint())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ThemeManifest {
+    background: String,
+    footer: Option<String>,
+    #[serde(default)]
+    fonts: ManifestFonts,
+    #[serde(default)]
+    colors: ManifestColors,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ManifestFonts {
+    bold: Option<String>,
+    light: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ManifestColors {
+    text: Option<String>,
+    footer: Option<String>,
+    brand: Option<String>,
+    done: Option<String>,
+    pending: Option<String>,
+    progress: Option<String>,
+    progress_track: Option<String>,
+}
+
+impl ThemeManifest {
+    fn read(dir: &Path) -> anyhow::Result<Self> {
+        let toml_path = dir.join("theme.toml");
+        if toml_path.is_file() {
+            return Ok(toml::from_str(&std::fs::read_to_string(toml_path)?)?);
+        }
+        let json_path = dir.join("theme.json");
+        if json_path.is_file() {
+            return Ok(serde_json::from_str(&std::fs::read_to_string(json_path)?)?);
+        }
+        anyhow::bail!("Missing theme.toml or theme.json")
+    }
+}
+
+fn image_dimensions(data: &[u8]) -> anyhow::Result<(u32, u32)> {
+    Ok(image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()?
+        .into_dimensions()?)
+}
+
+/// Parse `#RRGGBB` or `#RRGGBBAA` into RGBA.
+pub fn parse_color(value: Option<&str>) -> anyhow::Result<Option<[u8; 4]>> {
+    let value = match value {
+        Some(value) => value.trim_start_matches('#'),
+        None => return Ok(None),
+    };
+    let bytes = hex::decode(value)?;
+    match bytes.as_slice() {
+        [r, g, b] => Ok(Some([*r, *g, *b, 255])),
+        [r, g, b, a] => Ok(Some([*r, *g, *b, *a])),
+        _ => anyhow::bail!("Invalid color `{}`, expected #RRGGBB or #RRGGBBAA", value),
+    }
+}
+
+pub struct ThemeRegistry {
+    themes: HashMap<String, Arc<Theme>>,
+}
+
+impl ThemeRegistry {
+    /// Load every theme inside `dir`, always including the built-in default.
+    ///
+    /// Broken themes are logged and skipped so they never take the server down.
+    pub fn load(dir: Option<PathBuf>) -> Self {
+        let mut themes = HashMap::new();
+        themes.insert(DEFAULT_THEME.to_string(), Arc::new(Theme::builtin()));
+
+        let entries = match dir.as_deref().map(std::fs::read_dir) {
+            Some(Ok(entries)) => entries,
+            Some(Err(err)) => {
+                warn!("Unable to read themes directory {:?}: {}", dir, err);
+                return ThemeRegistry { themes };
+            }
+            None => return ThemeRegistry { themes },
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_lowercase();
+            if name == DEFAULT_THEME {
+                warn!("Ignoring theme {:?}, `default` is reserved", path);
+                continue;
+            }
+            match Theme::load(&name, &path) {
+                Ok(theme) => {
+                    info!("Loaded theme: {}", name);
+                    themes.insert(name, Arc::new(theme));
+                }
+                Err(err) => warn!("Failed to load theme {:?}: {}", path, err),
+            }
+        }
+
+        ThemeRegistry { themes }
+    }
+
+    /// Get a theme by name, `None` selects the default theme.
+    pub fn get(&self, name: Option<&str>) -> Option<Arc<Theme>> {
+        let name = name.unwrap_or(DEFAULT_THEME).to_lowercase();
+        self.themes.get(&name).cloned()
+    }
+}
@@ -0,0 +1,250 @@
+/// Shared HTTP client for upstream providers and analytics
+///
+/// One pooled client is built at startup and kept in `AppState`. GET requests are retried
+/// with jittered exponential backoff when the upstream times out, cannot be reached or
+/// answers with a 5xx, since those are usually transient.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use reqwest::{header, redirect, IntoUrl, Response, Url};
+use tracing::{info, warn};
+
+use crate::{
+    env::get_env,
+    fetch::{check_url, find_blocked, host_matches, BlockedUrl, HostAllowlist, PublicResolver},
+    metrics::Metrics,
+};
+
+static USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/74.0.3729.115 Safari/537.36";
+
+/// Longest wait between two retries, whatever the attempt count.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Provider label of upstream metrics by host, anything else is `other`.
+static PROVIDER_HOSTS: [(&str, &str); 13] = [
+    ("bandcamp.com", "bandcamp"),
+    ("bcbits.com", "bandcamp"),
+    ("soundcloud.com", "soundcloud"),
+    ("sndcdn.com", "soundcloud"),
+    ("youtube.com", "ytm"),
+    ("ytimg.com", "ytm"),
+    ("spotify.com", "spotify"),
+    ("scdn.co", "spotify"),
+    ("spotifycdn.com", "spotify"),
+    ("apple.com", "apple"),
+    ("mzstatic.com", "apple"),
+    ("discordapp.com", "discord"),
+    ("discordapp.net", "discord"),
+];
+
+fn provider_of(url: &Url) -> &'static str {
+    let host = url.host_str().unwrap_or_default();
+    PROVIDER_HOSTS
+        .iter()
+        .find(|(domain, _)| host_matches(host, domain))
+        .map_or("other", |(_, provider)| provider)
+}
+
+pub struct Upstream {
+    client: reqwest::Client,
+    /// Hosts user supplied URLs may reach, `None` for our own provider URLs.
+    hosts: Option<Arc<HostAllowlist>>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    max_redirects: usize,
+    /// Largest response body read by [`Upstream::bytes`] and [`Upstream::text`].
+    max_body_bytes: usize,
+    retries: u32,
+    backoff: Duration,
+    metrics: Arc<Metrics>,
+}
+
+fn env_number(key: &str, default: u64) -> u64 {
+    get_env(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+impl Upstream {
+    /// Build the client from the `UPSTREAM_*` variables, see `.env.example`.
+    pub fn from_env(metrics: Arc<Metrics>) -> Self {
+        let mut upstream = Upstream {
+            client: reqwest::Client::new(),
+            hosts: None,
+            connect_timeout: Duration::from_secs(env_number("UPSTREAM_CONNECT_TIMEOUT_SECS", 5)),
+            read_timeout: Duration::from_secs(env_number("UPSTREAM_READ_TIMEOUT_SECS", 10)),
+            max_redirects: env_number("UPSTREAM_MAX_REDIRECTS", 5) as usize,
+            max_body_bytes: (env_number("UPSTREAM_MAX_BODY_MB", 10) * 1024 * 1024) as usize,
+            retries: env_number("UPSTREAM_RETRIES", 2) as u32,
+            backoff: Duration::from_millis(env_number("UPSTREAM_RETRY_BACKOFF_MS", 250)),
+            metrics,
+        };
+        upstream.client = upstream
+            .builder()
+            .redirect(match upstream.max_redirects {
+                0 => redirect::Policy::none(),
+                max => redirect::Policy::limited(max),
+            })
+            .build()
+            .expect("HTTP client can be built");
+        info!(
+            "Upstream client: {:?} connect, {:?} read timeout, {} redirects, {} retries",
+            upstream.connect_timeout,
+            upstream.read_timeout,
+            upstream.max_redirects,
+            upstream.retries
+        );
+        upstream
+    }
+
+    /// A client with the same settings for user supplied URLs. They must pass
+    /// [`check_url`] against `hosts`, on every redirect too, and names resolving to a
+    /// private address are refused.
+    pub fn restricted(&self, hosts: HostAllowlist) -> Self {
+        let hosts = Arc::new(hosts);
+        let max_redirects = self.max_redirects;
+        let redirect_hosts = hosts.clone();
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > max_redirects {
+                return attempt.error(BlockedUrl("Too many redirects".to_string()));
+            }
+            match check_url(attempt.url(), &redirect_hosts) {
+                Ok(()) => attempt.follow(),
+                Err(blocked) => attempt.error(blocked),
+            }
+        });
+        let client = self
+            .builder()
+            .redirect(policy)
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .expect("HTTP client can be built");
+
+        Upstream {
+            client,
+            hosts: Some(hosts),
+            metrics: self.metrics.clone(),
+            ..*self
+        }
+    }
+
+    fn builder(&self) -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+    }
+
+    /// Check `url` before requesting it, any URL passes unless the client is restricted.
+    pub fn check(&self, url: &Url) -> Result<(), BlockedUrl> {
+        match &self.hosts {
+            Some(hosts) => check_url(url, hosts),
+            None => Ok(()),
+        }
+    }
+
+    /// The underlying client, for requests that must not be retried.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Wait before retry number `attempt`, doubling every time with up to 50% jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let base = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_BACKOFF);
+        base.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// GET `url`, retrying timeouts, connection errors and 5xx answers.
+    pub async fn get(&self, url: impl IntoUrl) -> reqwest::Result<Response> {
+        self.get_with_headers(url, header::HeaderMap::new()).await
+    }
+
+    /// Like [`Upstream::get`] with extra request headers.
+    pub async fn get_with_headers(
+        &self,
+        url: impl IntoUrl,
+        headers: header::HeaderMap,
+    ) -> reqwest::Result<Response> {
+        let url = url.into_url()?;
+        let provider = provider_of(&url);
+        let mut attempt = 0;
+        loop {
+            let start = Instant::now();
+            let res = self
+                .client
+                .get(url.clone())
+                .headers(headers.clone())
+                .send()
+                .await;
+            self.metrics
+                .upstream_duration
+                .with_label_values(&[provider])
+                .observe(start.elapsed().as_secs_f64());
+            let status = match &res {
+                Ok(response) => response.status().as_str().to_string(),
+                Err(_) => "error".to_string(),
+            };
+            self.metrics
+                .upstream_requests
+                .with_label_values(&[provider, &status])
+                .inc();
+            let retryable = match &res {
+                Ok(response) => response.status().is_server_error(),
+                Err(err) => (err.is_timeout() || err.is_connect()) && find_blocked(err).is_none(),
+            };
+            if !retryable || attempt >= self.retries {
+                return res;
+            }
+            let wait = self.backoff(attempt);
+            match &res {
+                Ok(response) => warn!(
+                    "Upstream {} answered {}, retrying in {:?}",
+                    url,
+                    response.status(),
+                    wait
+                ),
+                Err(err) => warn!("Upstream {} failed: {}, retrying in {:?}", url, err, wait),
+            }
+            attempt += 1;
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Read a response body, failing once it grows past `max_bytes`.
+    pub async fn bytes_limited(
+        &self,
+        mut response: Response,
+        max_bytes: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        if let Some(length) = response.content_length() {
+            if length as usize > max_bytes {
+                anyhow::bail!("Response is too large: {} bytes", length);
+            }
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if data.len() + chunk.len() > max_bytes {
+                anyhow::bail!("Response is larger than {} bytes", max_bytes);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    /// Read a response body up to the configured size limit.
+    pub async fn bytes(&self, response: Response) -> anyhow::Result<Vec<u8>> {
+        self.bytes_limited(response, self.max_body_bytes).await
+    }
+
+    /// Read a response body as text up to the configured size limit.
+    pub async fn text(&self, response: Response) -> anyhow::Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes(response).await?).into_owned())
+    }
+}